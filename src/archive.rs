@@ -0,0 +1,279 @@
+use anyhow::{bail, Result, Context};
+use serde::{Serialize, Deserialize};
+use zip::ZipArchive;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, Read, Take},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{Command, Child, ChildStdout, Stdio},
+};
+
+use libdogd::{log_debug, LogPriority, log_rust_error};
+
+/// What an emulator gets for a ROM inside an archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveMode {
+    /// The archive is unpacked into a temporary directory and the ROM in it is passed
+    #[default]
+    Extract,
+    /// The archive itself, for emulators that read them
+    Pass,
+}
+
+/// Whether `path` is an archive that can be looked into
+pub fn is_archive(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("zip") || e.eq_ignore_ascii_case("7z"))
+}
+
+/// A file in an archive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    /// Path inside the archive
+    pub path: PathBuf,
+    /// Unpacked size
+    pub size: u64,
+    /// Where it starts when every file in the archive is unpacked one after another, in the
+    /// order they're listed in
+    pub offset: u64,
+    /// As stored in the archive, which saves reading the file to check it against a DAT
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc: Option<u32>,
+}
+
+/// The files in a zip, read from its central directory without unpacking anything
+fn zip_files(path: &Path) -> Result<Vec<ArchiveFile>> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    let mut files: Vec<ArchiveFile> = Vec::new();
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i)?;
+        if !file.is_dir() {
+            files.push(ArchiveFile {
+                path: PathBuf::from(OsStr::from_bytes(file.name_raw())),
+                size: file.size(),
+                offset: files.last().map_or(0, |f| f.offset + f.size),
+                crc: Some(file.crc32()),
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// The files in a 7z, 7z headers are compressed so the 7z tool lists them
+fn sevenz_files(path: &Path) -> Result<Vec<ArchiveFile>> {
+    let output = Command::new("7z").arg("l").arg("-ba").arg("-slt").arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context("Failed to run 7z")?;
+    if !output.status.success() {
+        bail!("7z failed with {}", output.status);
+    }
+
+    // Blocks of "Key = value" lines, one per file
+    let mut files: Vec<ArchiveFile> = Vec::new();
+    for block in output.stdout.split(|&b| b == b'\n').collect::<Vec<_>>().split(|l| l.trim_ascii().is_empty()) {
+        let field = |key: &[u8]| block.iter().find_map(|l| l.strip_prefix(key)).map(|v| v.trim_ascii_end());
+        let is_dir = field(b"Attributes = ").is_some_and(|a| a.starts_with(b"D"));
+        let number = |key: &[u8], radix| field(key).and_then(|v| std::str::from_utf8(v).ok()).and_then(|v| u64::from_str_radix(v, radix).ok());
+        if let (Some(name), false) = (field(b"Path = "), is_dir) {
+            files.push(ArchiveFile {
+                path: PathBuf::from(OsStr::from_bytes(name)),
+                size: number(b"Size = ", 10).unwrap_or(0),
+                offset: files.last().map_or(0, |f| f.offset + f.size),
+                crc: number(b"CRC = ", 16).and_then(|c| c.try_into().ok()),
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// The files in an archive
+pub fn archive_files(path: &Path) -> Result<Vec<ArchiveFile>> {
+    if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("7z")) {
+        sevenz_files(path)
+    } else {
+        zip_files(path)
+    }
+}
+
+/// One file of an archive, unpacked as it's read
+pub struct ArchiveReader {
+    child: Child,
+    stdout: Take<ChildStdout>,
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        // unzip and 7z stop writing when something goes wrong, that's no reason to hash half a file
+        if n == 0 && !buf.is_empty() && self.stdout.limit() > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ended early"));
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ArchiveReader {
+    /// Whatever wasn't read doesn't have to be unpacked
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Reads `file` out of `archive` without writing anything to disk. unzip and 7z would take
+/// the name as a wildcard, so everything gets unpacked to a pipe and the files in front skipped.
+pub fn open_in_archive(archive: &Path, file: &ArchiveFile) -> Result<ArchiveReader> {
+    let mut cmd = if archive.extension().is_some_and(|e| e.eq_ignore_ascii_case("7z")) {
+        let mut cmd = Command::new("7z");
+        cmd.arg("e").arg("-so").arg(archive);
+        cmd
+    } else {
+        let mut cmd = Command::new("unzip");
+        cmd.arg("-p").arg(archive);
+        cmd
+    };
+    let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn()
+        .with_context(|| format!("Failed to run {}", cmd.get_program().to_string_lossy()))?;
+    let Some(stdout) = child.stdout.take() else {
+        bail!("No output from {}", cmd.get_program().to_string_lossy());
+    };
+    let mut reader = ArchiveReader {
+        child,
+        stdout: stdout.take(file.offset),
+    };
+
+    let skipped = io::copy(&mut reader, &mut io::sink()).with_context(|| format!("Failed to read {}", archive.display()))?;
+    if skipped < file.offset {
+        bail!("{} ended before {}", archive.display(), file.path.display());
+    }
+    reader.stdout.set_limit(file.size);
+    Ok(reader)
+}
+
+/// An archive unpacked into a temporary directory, removed again when dropped
+pub struct Extracted {
+    dir: PathBuf,
+}
+
+impl Extracted {
+    pub fn new(archive: &Path) -> Result<Extracted> {
+        // Only one entry runs at a time, anything left here is from a run that got cut short
+        let dir = env::temp_dir().join(format!("smenu-{}", std::process::id()));
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e).with_context(|| format!("Failed to clear {}", dir.display()));
+            }
+        }
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let extracted = Extracted { dir };
+
+        log_debug(format!("Extracting {} into {}", archive.display(), extracted.dir.display()));
+        let mut cmd = if archive.extension().is_some_and(|e| e.eq_ignore_ascii_case("7z")) {
+            let mut out = OsString::from("-o");
+            out.push(&extracted.dir);
+            let mut cmd = Command::new("7z");
+            cmd.arg("x").arg("-y").arg(out).arg(archive);
+            cmd
+        } else {
+            let mut cmd = Command::new("unzip");
+            cmd.arg("-o").arg("-qq").arg(archive).arg("-d").arg(&extracted.dir);
+            cmd
+        };
+        let status = cmd.stdin(Stdio::null()).stdout(Stdio::null()).status()
+            .with_context(|| format!("Failed to run {}", cmd.get_program().to_string_lossy()))?;
+        if !status.success() {
+            bail!("Failed to extract {}: {}", archive.display(), status);
+        }
+        Ok(extracted)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for Extracted {
+    fn drop(&mut self) {
+        log_debug(format!("Removing {}", self.dir.display()));
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            log_rust_error(&e, format!("Failed to remove {}", self.dir.display()), LogPriority::Error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Made with Python's zipfile, stored rather than deflated: a `docs/` directory, `readme.txt`
+    /// holding "hi\n" and `Game (USA).nes`, an iNES header followed by "ROMDATA!" 4 times
+    const ZIP: &[u8] = b"\
+\x50\x4b\x03\x04\x14\x00\x00\x00\x00\x00\x00\x00\x21\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+\x00\x00\x05\x00\x00\x00\x64\x6f\x63\x73\x2f\x50\x4b\x03\x04\x14\x00\x00\x00\x00\x00\x1d\x28\x50\
+\x5d\x7a\x7a\x6f\xed\x03\x00\x00\x00\x03\x00\x00\x00\x0a\x00\x00\x00\x72\x65\x61\x64\x6d\x65\x2e\
+\x74\x78\x74\x68\x69\x0a\x50\x4b\x03\x04\x14\x00\x00\x00\x00\x00\x1d\x28\x50\x5d\x5d\x3c\x93\x80\
+\x30\x00\x00\x00\x30\x00\x00\x00\x0e\x00\x00\x00\x47\x61\x6d\x65\x20\x28\x55\x53\x41\x29\x2e\x6e\
+\x65\x73\x4e\x45\x53\x1a\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x52\x4f\x4d\x44\x41\x54\
+\x41\x21\x52\x4f\x4d\x44\x41\x54\x41\x21\x52\x4f\x4d\x44\x41\x54\x41\x21\x52\x4f\x4d\x44\x41\x54\
+\x41\x21\x50\x4b\x01\x02\x14\x03\x14\x00\x00\x00\x00\x00\x00\x00\x21\x00\x00\x00\x00\x00\x00\x00\
+\x00\x00\x00\x00\x00\x00\x05\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x01\x00\x00\x00\x00\
+\x64\x6f\x63\x73\x2f\x50\x4b\x01\x02\x14\x03\x14\x00\x00\x00\x00\x00\x1d\x28\x50\x5d\x7a\x7a\x6f\
+\xed\x03\x00\x00\x00\x03\x00\x00\x00\x0a\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80\x01\x23\
+\x00\x00\x00\x72\x65\x61\x64\x6d\x65\x2e\x74\x78\x74\x50\x4b\x01\x02\x14\x03\x14\x00\x00\x00\x00\
+\x00\x1d\x28\x50\x5d\x5d\x3c\x93\x80\x30\x00\x00\x00\x30\x00\x00\x00\x0e\x00\x00\x00\x00\x00\x00\
+\x00\x00\x00\x00\x00\x80\x01\x4e\x00\x00\x00\x47\x61\x6d\x65\x20\x28\x55\x53\x41\x29\x2e\x6e\x65\
+\x73\x50\x4b\x05\x06\x00\x00\x00\x00\x03\x00\x03\x00\xa7\x00\x00\x00\xaa\x00\x00\x00\x00\x00";
+
+    /// Writes `data` to a zip file of its own, tests run in parallel
+    fn write_zip(name: &str, data: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("smenu-test-{}-{}.zip", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn zip_listing() {
+        let path = write_zip("listing", ZIP);
+        let files = zip_files(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(files, vec![
+            ArchiveFile { path: PathBuf::from("readme.txt"), size: 3, offset: 0, crc: Some(0xED6F_7A7A) },
+            ArchiveFile { path: PathBuf::from("Game (USA).nes"), size: 48, offset: 3, crc: Some(0x8093_3C5D) },
+        ]);
+    }
+
+    #[test]
+    fn zip_central_directory_out_of_bounds() {
+        // Central directory size in the end of central directory record, made huge
+        let mut zip = ZIP.to_vec();
+        let at = zip.len() - 22 + 12;
+        zip[at..at + 4].copy_from_slice(&0x7FFF_FFFFu32.to_le_bytes());
+        let path = write_zip("bounds", &zip);
+        let result = zip_files(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn read_from_zip() {
+        if Command::new("unzip").arg("-v").stdout(Stdio::null()).status().is_err() {
+            return;
+        }
+        let path = write_zip("read", ZIP);
+        let files = zip_files(&path).unwrap();
+        let mut data = Vec::new();
+        let result = open_in_archive(&path, &files[1]).and_then(|mut r| Ok(r.read_to_end(&mut data)?));
+        fs::remove_file(&path).unwrap();
+
+        result.unwrap();
+        assert_eq!(data.len(), 48);
+        assert!(data.starts_with(b"NES\x1A"));
+        assert!(data.ends_with(b"ROMDATA!"));
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+    collections::BTreeMap,
+};
+
+use libdogd::{LogPriority, log_rust_error};
+
+use crate::state::write_atomic;
+use crate::vars::data_dir;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Cached<T> {
+    /// Size and modification time of the file, when either changes it's read again
+    size: u64,
+    mtime: u64,
+    value: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct CacheFile<T> {
+    /// Keyed by path
+    files: BTreeMap<String, Cached<T>>,
+}
+
+impl<T> Default for CacheFile<T> {
+    fn default() -> Self {
+        Self { files: BTreeMap::new() }
+    }
+}
+
+/// Something worked out from files that's slow to get, like hashes or archive listings, kept
+/// in `${DATA}/smenu/<name>.toml` so only new and changed files get read on a rescan
+pub struct FileCache<T> {
+    path: PathBuf,
+    old: CacheFile<T>,
+    /// Only what was looked up during this scan, so files that are gone drop out
+    new: CacheFile<T>,
+}
+
+impl<T: Clone + PartialEq + Serialize + DeserializeOwned> FileCache<T> {
+    pub fn load(name: &str) -> FileCache<T> {
+        let path = data_dir().join(format!("smenu/{}.toml", name));
+        let old = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|e| {
+                log_rust_error(&e, format!("Failed to parse {}, starting over", path.display()), LogPriority::Error);
+                CacheFile::default()
+            }),
+            Err(_) => CacheFile::default(),
+        };
+        FileCache {
+            path,
+            old,
+            new: CacheFile::default(),
+        }
+    }
+
+    /// What's cached for `key` if `file` hasn't changed since, otherwise what `f` makes of it.
+    /// `key` is usually `file`, but can be something inside it.
+    pub fn get(&mut self, key: &Path, file: &Path, f: impl FnOnce() -> Option<T>) -> Option<T> {
+        let meta = fs::metadata(file).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        // Paths that aren't UTF-8 can't be keys in TOML, they just don't get cached
+        let Some(key) = key.to_str() else { return f() };
+
+        if let Some(cached) = self.old.files.get(key).filter(|c| c.size == meta.len() && c.mtime == mtime) {
+            let value = cached.value.clone();
+            self.new.files.insert(key.to_string(), cached.clone());
+            return Some(value);
+        }
+
+        let value = f()?;
+        // Same for anything else TOML can't hold, it would stop the whole cache from being saved
+        if toml::Value::try_from(&value).is_ok() {
+            self.new.files.insert(key.to_string(), Cached {
+                size: meta.len(),
+                mtime,
+                value: value.clone(),
+            });
+        }
+        Some(value)
+    }
+
+    /// Writes the cache if anything changed since it was loaded
+    pub fn save(&self) {
+        if self.new.files == self.old.files {
+            return;
+        }
+        let result = toml::to_string(&self.new)
+            .context("Failed to serialize the cache")
+            .and_then(|text| write_atomic(&self.path, text.as_bytes()));
+        if let Err(e) = result {
+            log_rust_error(&*e, format!("Failed to save {}", self.path.display()), LogPriority::Error);
+        }
+    }
+}
@@ -0,0 +1,996 @@
+use serde::{Serialize, Deserialize};
+use anyhow::{anyhow, Result, Context};
+use std::{
+    env,
+    fs,
+    iter,
+    path::{Path, PathBuf},
+    collections::{BTreeSet, HashMap},
+};
+
+use libdogd::{log_debug, log_info};
+
+use crate::archive::ArchiveMode;
+use crate::dat::Dump;
+use crate::diagnostics::{ConfigFile, Problem};
+use crate::menu::RomTab;
+use crate::migrate::CURRENT_VERSION;
+use crate::sort::SortMode;
+
+/// A tab holding every item that names this category
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Category {
+    pub name: String,
+    /// Shown on the tab instead of the name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+impl Category {
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MenuEntry {
+    pub name: String,
+    /// Name of the category the item is listed under
+    pub category: String,
+    /// Folder in the category's tab the item is in, `/` separated for nested ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    pub uses_wayland: bool,
+    pub executable: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    /// Asks before running it, for things that are bad to press by accident
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub confirm: bool,
+    /// Asked instead of the generic question
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_message: Option<String>,
+    /// Passed after `args`, for ROMs. Kept as a path all the way to the emulator since file
+    /// names don't have to be valid UTF-8.
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
+    /// Path inside the `file` archive of what actually gets passed, after unpacking it
+    #[serde(skip)]
+    pub extract: Option<PathBuf>,
+    /// See `MenuLayout::mark_builtin`
+    #[serde(skip)]
+    pub builtin: bool,
+}
+
+impl MenuEntry {
+    /// The question asked before running it
+    pub fn confirm_message(&self) -> String {
+        self.confirm_message.clone().unwrap_or_else(|| format!("Run {}?", self.name))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Emulator {
+    pub executable: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    pub systems: Vec<String>,
+    /// What it gets for ROMs inside archives
+    #[serde(default)]
+    pub archives: ArchiveMode,
+    /// See `MenuLayout::mark_builtin`
+    #[serde(skip)]
+    pub builtin: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct System {
+    pub name: String,
+    pub rom_directory: PathBuf,
+    /// Ordered so the dumped config comes out the same every time
+    pub file_extensions: BTreeSet<String>,
+    /// Initial order of the tab, can be switched from the menu
+    #[serde(default)]
+    pub sort: SortMode,
+    /// Sort "The Legend of Zelda" under L
+    #[serde(default)]
+    pub ignore_articles: bool,
+    /// How many levels of subdirectories get scanned, 3 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<usize>,
+    #[serde(default)]
+    pub subfolders: Subfolders,
+    /// Looks into zip and 7z archives for ROMs with one of `file_extensions`
+    #[serde(default)]
+    pub archives: bool,
+    /// Logiqx XML or clrmamepro DAT the ROMs are checked against, giving them their proper titles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dat_file: Option<PathBuf>,
+    /// See `MenuLayout::mark_builtin`
+    #[serde(skip)]
+    pub builtin: bool,
+}
+
+impl System {
+    pub fn depth(&self) -> usize {
+        self.depth.unwrap_or(3)
+    }
+}
+
+/// How ROMs in subdirectories of a system's ROM directory are shown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subfolders {
+    /// As folders that can be opened, like the directories they're in
+    #[default]
+    Folders,
+    /// Listed along with the ones at the top
+    Flatten,
+}
+
+/// How the built-in default.toml is used as the base layer under the config
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Defaults {
+    /// Start from an empty menu instead of the defaults
+    pub include: bool,
+    /// Names of default categories to drop
+    pub remove_categories: Vec<String>,
+    /// Names of default items to drop
+    pub remove_items: Vec<String>,
+    /// Executables of default emulators to drop
+    pub remove_emulators: Vec<PathBuf>,
+    /// Names of default systems to drop
+    pub remove_systems: Vec<String>,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Self {
+            include: true,
+            remove_categories: Vec::new(),
+            remove_items: Vec::new(),
+            remove_emulators: Vec::new(),
+            remove_systems: Vec::new(),
+        }
+    }
+}
+
+/// Which tabs show up and in what order. Tabs are referred to by category or system name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Tabs {
+    /// Listed tabs come first, in this order, the rest follow in the order they're defined in
+    pub order: Vec<String>,
+    pub hidden: Vec<String>,
+    /// Tab the menu opens on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup: Option<String>,
+    /// How many entries the Recent tab lists, 10 by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent: Option<usize>,
+    /// Whether the Recent tab has a button continuing the last game, on by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continue_last: Option<bool>,
+}
+
+impl Tabs {
+    /// Puts `over` on top of these settings, its order and startup tab win if set
+    fn layer(&mut self, over: Tabs) {
+        if !over.order.is_empty() {
+            self.order = over.order;
+        }
+        self.hidden.extend(over.hidden);
+        if over.startup.is_some() {
+            self.startup = over.startup;
+        }
+        if over.recent.is_some() {
+            self.recent = over.recent;
+        }
+        if over.continue_last.is_some() {
+            self.continue_last = over.continue_last;
+        }
+    }
+
+    pub fn recent_count(&self) -> usize {
+        self.recent.unwrap_or(10)
+    }
+
+    pub fn continue_last(&self) -> bool {
+        self.continue_last.unwrap_or(true)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.order.iter().chain(self.hidden.iter()).chain(self.startup.iter())
+    }
+
+    /// Sorts and hides tabs as configured. sgui always opens on the first tab, so the startup
+    /// tab is rotated to the front, which keeps the order when wrapping around.
+    pub fn arrange<T>(&self, mut tabs: Vec<T>, name: impl Fn(&T) -> &str) -> Vec<T> {
+        tabs.retain(|t| !self.hidden.iter().any(|h| h == name(t)));
+        tabs.sort_by_key(|t| self.order.iter().position(|o| o == name(t)).unwrap_or(self.order.len()));
+        if let Some(startup) = &self.startup {
+            if let Some(i) = tabs.iter().position(|t| name(t) == startup) {
+                tabs.rotate_left(i);
+            }
+        }
+        tabs
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MenuLayout {
+    #[serde(default)]
+    pub defaults: Defaults,
+    #[serde(default)]
+    pub tabs: Tabs,
+    #[serde(rename = "category", default)]
+    pub categories: Vec<Category>,
+    #[serde(rename = "item", default)]
+    pub items: Vec<MenuEntry>,
+    #[serde(rename = "emulator", default)]
+    pub emulators: Vec<Emulator>,
+    #[serde(rename = "system", default)]
+    pub systems: Vec<System>,
+    /// Everything that went wrong while loading, shown in the menu
+    #[serde(skip)]
+    pub problems: Vec<Problem>,
+}
+
+/// A ROM entry generated from a system's directory, as it shows up in the dumped config
+#[derive(Serialize)]
+struct GeneratedEntry<'a> {
+    tab: &'a str,
+    name: &'a str,
+    uses_wayland: bool,
+    executable: &'a Path,
+    args: &'a [String],
+    /// Only for reading, not valid UTF-8 in places
+    file: String,
+    env: &'a [(String, String)],
+    /// Missing when there's no DAT file or the ROM hasn't been hashed yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dump: Option<Dump>,
+}
+
+/// Everything smenu ended up with after merging, layering and expanding the config
+#[derive(Serialize)]
+struct EffectiveConfig<'a> {
+    version: i64,
+    defaults: &'a Defaults,
+    tabs: &'a Tabs,
+    #[serde(rename = "category")]
+    categories: &'a [Category],
+    #[serde(rename = "item")]
+    items: &'a [MenuEntry],
+    #[serde(rename = "emulator")]
+    emulators: &'a [Emulator],
+    #[serde(rename = "system")]
+    systems: &'a [System],
+    #[serde(rename = "generated")]
+    generated: Vec<GeneratedEntry<'a>>,
+}
+
+/// Remembers which file defined what, so conflicts between fragments can name both sides
+#[derive(Default)]
+struct Origins {
+    categories: HashMap<String, PathBuf>,
+    items: HashMap<String, PathBuf>,
+    systems: HashMap<String, PathBuf>,
+    emulated_systems: HashMap<String, PathBuf>,
+}
+
+impl MenuLayout {
+    fn parse(file: &ConfigFile) -> Result<MenuLayout> {
+        let mut problems = Vec::new();
+        let mut table = file.table(&mut problems)?;
+        let layout = MenuLayout {
+            defaults: file.parse_table(&mut table, "defaults", &mut problems),
+            tabs: file.parse_table(&mut table, "tabs", &mut problems),
+            categories: file.parse_array(&mut table, "category", &mut problems),
+            items: file.parse_array(&mut table, "item", &mut problems),
+            emulators: file.parse_array(&mut table, "emulator", &mut problems),
+            systems: file.parse_array(&mut table, "system", &mut problems),
+            problems: Vec::new(),
+        };
+        file.unknown_fields(table, &mut problems);
+
+        Ok(MenuLayout { problems, ..layout })
+    }
+
+    /// Serializes the layout back to TOML along with the entries generated from ROM directories
+    pub fn dump(&self, rom_tabs: &[RomTab]) -> Result<String> {
+        let generated = rom_tabs.iter()
+            .flat_map(|tab| tab.roms.iter().map(move |rom| {
+                let emulator = &self.emulators[rom.emulator];
+                GeneratedEntry {
+                    tab: &tab.name,
+                    name: &rom.name,
+                    uses_wayland: true,
+                    executable: &emulator.executable,
+                    args: &emulator.args,
+                    file: rom.path.to_string_lossy().into_owned(),
+                    env: &emulator.env,
+                    dump: rom.dump,
+                }
+            }))
+            .collect();
+
+        let effective = EffectiveConfig {
+            version: CURRENT_VERSION,
+            defaults: &self.defaults,
+            tabs: &self.tabs,
+            categories: &self.categories,
+            items: &self.items,
+            emulators: &self.emulators,
+            systems: &self.systems,
+            generated,
+        };
+        // Through a Value, which writes plain values before tables. Serializing directly fails
+        // on empty arrays like `generated = []` coming after the [defaults] table.
+        toml::Value::try_from(&effective)
+            .context("Failed to convert the effective config")
+            .and_then(|v| toml::to_string(&v).context("Failed to serialize the effective config"))
+    }
+
+    /// Appends everything from `other` that doesn't clash with what's already loaded,
+    /// reporting every clash as a problem
+    fn merge(&mut self, other: MenuLayout, source: &Path, origins: &mut Origins) {
+        let mut conflicts = Vec::new();
+        self.problems.extend(other.problems);
+
+        self.defaults.include &= other.defaults.include;
+        self.defaults.remove_categories.extend(other.defaults.remove_categories);
+        self.defaults.remove_items.extend(other.defaults.remove_items);
+        self.defaults.remove_emulators.extend(other.defaults.remove_emulators);
+        self.defaults.remove_systems.extend(other.defaults.remove_systems);
+
+        if !other.tabs.order.is_empty() && !self.tabs.order.is_empty() {
+            conflicts.push(format!("Tab order in {} replaces one set in an earlier file", source.display()));
+        }
+        if other.tabs.startup.is_some() && self.tabs.startup.is_some() {
+            conflicts.push(format!("Startup tab in {} replaces one set in an earlier file", source.display()));
+        }
+        self.tabs.layer(other.tabs);
+
+        for category in other.categories.into_iter() {
+            // Upgraded configs all declare the categories that used to be built in
+            if self.categories.contains(&category) {
+                continue;
+            }
+            if let Some(prev) = origins.categories.get(&category.name) {
+                conflicts.push(format!("Category {} in {} is already defined in {}", category.name, source.display(), prev.display()));
+                continue;
+            }
+            origins.categories.insert(category.name.clone(), source.to_path_buf());
+            self.categories.push(category);
+        }
+
+        for item in other.items.into_iter() {
+            let key = format!("{}/{}", item.category, item.name);
+            if let Some(prev) = origins.items.get(&key) {
+                conflicts.push(format!("Item {} in {} is already defined in {}", key, source.display(), prev.display()));
+                continue;
+            }
+            origins.items.insert(key, source.to_path_buf());
+            self.items.push(item);
+        }
+
+        'emulators: for emulator in other.emulators.into_iter() {
+            for system in emulator.systems.iter() {
+                if let Some(prev) = origins.emulated_systems.get(system) {
+                    conflicts.push(format!("Emulator {} in {} handles system {}, which already has an emulator in {}",
+                                           emulator.executable.display(), source.display(), system, prev.display()));
+                    continue 'emulators;
+                }
+            }
+            for system in emulator.systems.iter() {
+                origins.emulated_systems.insert(system.clone(), source.to_path_buf());
+            }
+            self.emulators.push(emulator);
+        }
+
+        for system in other.systems.into_iter() {
+            if let Some(prev) = origins.systems.get(&system.name) {
+                conflicts.push(format!("System {} in {} is already defined in {}", system.name, source.display(), prev.display()));
+                continue;
+            }
+            origins.systems.insert(system.name.clone(), source.to_path_buf());
+            self.systems.push(system);
+        }
+
+        self.problems.extend(conflicts.into_iter().map(|c| Problem {
+            source: Some(source.to_path_buf()),
+            position: None,
+            message: c,
+        }));
+    }
+
+    /// Puts `over` on top of this layout. Categories, items and systems with the same name replace
+    /// the ones here, emulators take over the systems they list, everything else gets added.
+    fn layer(mut self, over: MenuLayout) -> MenuLayout {
+        self.problems.extend(over.problems);
+
+        for category in over.categories.into_iter() {
+            match self.categories.iter_mut().find(|c| c.name == category.name) {
+                Some(existing) => *existing = category,
+                None => self.categories.push(category),
+            }
+        }
+
+        for item in over.items.into_iter() {
+            match self.items.iter_mut().find(|i| i.name == item.name && i.category == item.category) {
+                Some(existing) => *existing = item,
+                None => self.items.push(item),
+            }
+        }
+
+        for emulator in self.emulators.iter_mut() {
+            emulator.systems.retain(|s| !over.emulators.iter().any(|e| e.systems.contains(s)));
+        }
+        self.emulators.retain(|e| !e.systems.is_empty());
+        self.emulators.extend(over.emulators);
+
+        for system in over.systems.into_iter() {
+            match self.systems.iter_mut().find(|s| s.name == system.name) {
+                Some(existing) => *existing = system,
+                None => self.systems.push(system),
+            }
+        }
+
+        self.tabs.layer(over.tabs);
+        MenuLayout {
+            defaults: over.defaults,
+            ..self
+        }
+    }
+
+    /// Drops whatever `defaults` asks for from the built-in layout
+    fn strip_defaults(&mut self, defaults: &Defaults) {
+        if !defaults.include {
+            self.categories.clear();
+            self.items.clear();
+            self.emulators.clear();
+            self.systems.clear();
+            return;
+        }
+        self.categories.retain(|c| !defaults.remove_categories.contains(&c.name));
+        // Along with the items in dropped categories, they'd have no tab otherwise
+        self.items.retain(|i| !defaults.remove_items.contains(&i.name) && !defaults.remove_categories.contains(&i.category));
+        self.emulators.retain(|e| !defaults.remove_emulators.contains(&e.executable));
+        self.systems.retain(|s| !defaults.remove_systems.contains(&s.name));
+    }
+
+    /// Marks everything as coming from the built-in defaults. They're written for every device
+    /// at once, so missing executables and ROM directories aren't reported for them until a
+    /// config or overlay changes them.
+    fn mark_builtin(&mut self) {
+        self.items.iter_mut().for_each(|i| i.builtin = true);
+        self.emulators.iter_mut().for_each(|e| e.builtin = true);
+        self.systems.iter_mut().for_each(|s| s.builtin = true);
+    }
+}
+
+/// A per-user config layered on top of the system one. Entries are matched by name, items by
+/// category too when one is given: matching ones get the given fields overridden (or are
+/// removed with `hide = true`), the rest are added as new entries and have to be complete.
+#[derive(Debug, Default)]
+pub struct Overlay {
+    tabs: Tabs,
+    categories: Vec<CategoryOverlay>,
+    items: Vec<MenuEntryOverlay>,
+    /// Emulators can't be matched by name, user ones take precedence over system ones
+    emulators: Vec<Emulator>,
+    systems: Vec<SystemOverlay>,
+    problems: Vec<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CategoryOverlay {
+    name: String,
+    #[serde(default)]
+    hide: bool,
+    title: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MenuEntryOverlay {
+    name: String,
+    #[serde(default)]
+    hide: bool,
+    category: Option<String>,
+    parent: Option<String>,
+    uses_wayland: Option<bool>,
+    executable: Option<PathBuf>,
+    args: Option<Vec<String>>,
+    env: Option<Vec<(String, String)>>,
+    confirm: Option<bool>,
+    confirm_message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SystemOverlay {
+    name: String,
+    #[serde(default)]
+    hide: bool,
+    rom_directory: Option<PathBuf>,
+    file_extensions: Option<BTreeSet<String>>,
+    sort: Option<SortMode>,
+    ignore_articles: Option<bool>,
+    depth: Option<usize>,
+    subfolders: Option<Subfolders>,
+    archives: Option<bool>,
+    dat_file: Option<PathBuf>,
+}
+
+impl MenuEntryOverlay {
+    fn apply(self, entry: &mut MenuEntry) {
+        if let Some(category) = self.category {
+            entry.category = category;
+        }
+        if let Some(parent) = self.parent {
+            entry.parent = Some(parent);
+        }
+        if let Some(uses_wayland) = self.uses_wayland {
+            entry.uses_wayland = uses_wayland;
+        }
+        if let Some(executable) = self.executable {
+            entry.executable = executable;
+        }
+        if let Some(args) = self.args {
+            entry.args = args;
+        }
+        if let Some(env) = self.env {
+            entry.env = env;
+        }
+        if let Some(confirm) = self.confirm {
+            entry.confirm = confirm;
+        }
+        if let Some(confirm_message) = self.confirm_message {
+            entry.confirm_message = Some(confirm_message);
+        }
+    }
+
+    fn into_entry(self) -> Result<MenuEntry> {
+        let missing = |field| anyhow!("New item {} is missing field {}", self.name, field);
+        Ok(MenuEntry {
+            category: self.category.ok_or_else(|| missing("category"))?,
+            parent: self.parent,
+            uses_wayland: self.uses_wayland.ok_or_else(|| missing("uses_wayland"))?,
+            executable: self.executable.ok_or_else(|| missing("executable"))?,
+            args: self.args.unwrap_or_default(),
+            env: self.env.unwrap_or_default(),
+            confirm: self.confirm.unwrap_or_default(),
+            confirm_message: self.confirm_message,
+            file: None,
+            extract: None,
+            builtin: false,
+            name: self.name,
+        })
+    }
+}
+
+impl SystemOverlay {
+    fn apply(self, system: &mut System) {
+        if let Some(rom_directory) = self.rom_directory {
+            system.rom_directory = rom_directory;
+        }
+        if let Some(file_extensions) = self.file_extensions {
+            system.file_extensions = file_extensions;
+        }
+        if let Some(sort) = self.sort {
+            system.sort = sort;
+        }
+        if let Some(ignore_articles) = self.ignore_articles {
+            system.ignore_articles = ignore_articles;
+        }
+        if let Some(depth) = self.depth {
+            system.depth = Some(depth);
+        }
+        if let Some(subfolders) = self.subfolders {
+            system.subfolders = subfolders;
+        }
+        if let Some(archives) = self.archives {
+            system.archives = archives;
+        }
+        if let Some(dat_file) = self.dat_file {
+            system.dat_file = Some(dat_file);
+        }
+    }
+
+    fn into_system(self) -> Result<System> {
+        let missing = |field| anyhow!("New system {} is missing field {}", self.name, field);
+        Ok(System {
+            rom_directory: self.rom_directory.ok_or_else(|| missing("rom_directory"))?,
+            file_extensions: self.file_extensions.ok_or_else(|| missing("file_extensions"))?,
+            sort: self.sort.unwrap_or_default(),
+            ignore_articles: self.ignore_articles.unwrap_or_default(),
+            depth: self.depth,
+            subfolders: self.subfolders.unwrap_or_default(),
+            archives: self.archives.unwrap_or_default(),
+            dat_file: self.dat_file,
+            builtin: false,
+            name: self.name,
+        })
+    }
+}
+
+impl Overlay {
+    fn parse(file: &ConfigFile) -> Result<Overlay> {
+        let mut problems = Vec::new();
+        let mut table = file.table(&mut problems)?;
+        let overlay = Overlay {
+            tabs: file.parse_table(&mut table, "tabs", &mut problems),
+            categories: file.parse_array(&mut table, "category", &mut problems),
+            items: file.parse_array(&mut table, "item", &mut problems),
+            emulators: file.parse_array(&mut table, "emulator", &mut problems),
+            systems: file.parse_array(&mut table, "system", &mut problems),
+            problems: Vec::new(),
+        };
+        file.unknown_fields(table, &mut problems);
+
+        Ok(Overlay { problems, ..overlay })
+    }
+
+    pub fn apply(self, layout: &mut MenuLayout) {
+        layout.problems.extend(self.problems);
+        layout.tabs.layer(self.tabs);
+
+        for category in self.categories.into_iter() {
+            if category.hide {
+                layout.categories.retain(|c| c.name != category.name);
+                layout.items.retain(|i| i.category != category.name);
+            } else if let Some(existing) = layout.categories.iter_mut().find(|c| c.name == category.name) {
+                if category.title.is_some() {
+                    existing.title = category.title;
+                }
+            } else {
+                layout.categories.push(Category {
+                    name: category.name,
+                    title: category.title,
+                });
+            }
+        }
+
+        for item in self.items.into_iter() {
+            // Names only have to be unique within a category
+            let matches = |i: &MenuEntry| i.name == item.name && item.category.as_ref().is_none_or(|c| *c == i.category);
+            if item.hide {
+                layout.items.retain(|i| !matches(i));
+            } else if let Some(existing) = layout.items.iter_mut().find(|i| matches(i)) {
+                existing.builtin = false;
+                item.apply(existing);
+            } else {
+                match item.into_entry() {
+                    Ok(entry) => layout.items.push(entry),
+                    Err(e) => layout.problems.push(Problem::new(e.to_string())),
+                }
+            }
+        }
+
+        for (i, emulator) in self.emulators.into_iter().enumerate() {
+            layout.emulators.insert(i, emulator);
+        }
+
+        for system in self.systems.into_iter() {
+            if system.hide {
+                layout.systems.retain(|s| s.name != system.name);
+            } else if let Some(existing) = layout.systems.iter_mut().find(|s| s.name == system.name) {
+                existing.builtin = false;
+                system.apply(existing);
+            } else {
+                match system.into_system() {
+                    Ok(system) => layout.systems.push(system),
+                    Err(e) => layout.problems.push(Problem::new(e.to_string())),
+                }
+            }
+        }
+    }
+}
+
+/// Everywhere a per-user config can be, in order of preference: `$XDG_CONFIG_HOME/smenu/config.toml`,
+/// `~/.config/smenu/config.toml` and `/data/smenu/config.toml`
+pub fn user_overlay_paths() -> Vec<PathBuf> {
+    let xdg_config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+    xdg_config.map(|dir| dir.join("smenu/config.toml"))
+        .into_iter()
+        .chain(iter::once(PathBuf::from("/data/smenu/config.toml")))
+        .collect()
+}
+
+/// The first existing per-user config
+pub fn user_overlay_path() -> Option<PathBuf> {
+    user_overlay_paths().into_iter().find(|p| p.is_file())
+}
+
+pub fn load_overlay(p: &Path) -> Result<Overlay> {
+    let conf = fs::read_to_string(p).with_context(|| format!("Failed to read user config file {}", p.display()))?;
+    Overlay::parse(&ConfigFile { path: p, text: &conf })
+}
+
+/// Directory holding config fragments for a config file, `/etc/smenu.toml` -> `/etc/smenu.d`
+pub fn drop_in_dir(config_path: &Path) -> PathBuf {
+    config_path.with_extension("d")
+}
+
+/// Every `*.toml` file in `dir`, sorted by file name, which is the order they're merged in
+pub fn drop_in_fragments(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut fragments = Vec::new();
+    for file in fs::read_dir(dir).with_context(|| format!("Failed to open config directory {}", dir.display()))? {
+        let path = file?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+    Ok(fragments)
+}
+
+fn parse_config_file(p: &Path) -> Result<MenuLayout> {
+    let conf = fs::read_to_string(p).with_context(|| format!("Failed to read config file {}", p.display()))?;
+    MenuLayout::parse(&ConfigFile { path: p, text: &conf })
+}
+
+/// Loads `p`, merges every fragment from its drop-in directory into it and layers the result
+/// over the built-in defaults. Anything wrong with the files ends up in `problems`, an
+/// unparseable `p` too, leaving the fragments and the defaults. Without `p` it's the same.
+pub fn load_config(p: &Path) -> Result<MenuLayout> {
+    let mut config = MenuLayout::default();
+    let mut origins = Origins::default();
+    if p.exists() {
+        log_debug(format!("Loading config file {}", p.display()));
+        match parse_config_file(p) {
+            Ok(main) => config.merge(main, p, &mut origins),
+            Err(e) => config.problems.push(Problem::new(format!("{:#}", e))),
+        }
+    } else {
+        log_info(format!("Missing config file {}, using default", p.display()));
+    }
+
+    let fragments = match drop_in_fragments(&drop_in_dir(p)) {
+        Ok(f) => f,
+        Err(e) => {
+            config.problems.push(Problem::new(format!("{:#}", e)));
+            Vec::new()
+        },
+    };
+    for path in fragments {
+        log_debug(format!("Loading config fragment {}", path.display()));
+        match parse_config_file(&path) {
+            Ok(fragment) => config.merge(fragment, &path, &mut origins),
+            Err(e) => config.problems.push(Problem::new(format!("{:#}", e))),
+        }
+    }
+
+    let mut base = load_default_config()?;
+    base.strip_defaults(&config.defaults);
+    base.mark_builtin();
+    Ok(base.layer(config))
+}
+
+pub fn load_default_config() -> Result<MenuLayout> {
+    let conf = include_str!("default.toml");
+    MenuLayout::parse(&ConfigFile { path: Path::new("<built-in default config>"), text: conf })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(path: &str, text: &str) -> MenuLayout {
+        MenuLayout::parse(&ConfigFile { path: Path::new(path), text }).unwrap()
+    }
+
+    fn item(name: &str) -> String {
+        format!("[[item]]\nname = \"{}\"\ncategory = \"Tools\"\nuses_wayland = false\nexecutable = \"/bin/true\"\n", name)
+    }
+
+    /// A device or CI image without any ROMs still gets its config dumped, even though every
+    /// array after [defaults] and [tabs] can be empty
+    #[test]
+    fn dump_without_roms() {
+        let mut layout = load_default_config().unwrap();
+        layout.systems.clear();
+        for layout in [layout, MenuLayout::default()] {
+            let dump = layout.dump(&[]).unwrap();
+            let table: toml::value::Table = toml::from_str(&dump).unwrap();
+            assert_eq!(table.get("version").and_then(toml::Value::as_integer), Some(CURRENT_VERSION));
+            assert!(table.get("generated").and_then(toml::Value::as_array).is_some_and(Vec::is_empty));
+        }
+    }
+
+    /// Reloads compare dumps to tell whether anything changed
+    #[test]
+    fn dump_is_stable() {
+        let dump = || load_default_config().unwrap().dump(&[]).unwrap();
+        let first = dump();
+        for _ in 0..10 {
+            assert_eq!(dump(), first);
+        }
+    }
+
+    #[test]
+    fn broken_main_file() {
+        let dir = env::temp_dir().join(format!("smenu-test-{}-broken", std::process::id()));
+        fs::create_dir_all(dir.join("smenu.d")).unwrap();
+        fs::write(dir.join("smenu.toml"), "[[category]\nname = \"Typo\"\n").unwrap();
+        fs::write(dir.join("smenu.d/tools.toml"), "[[category]]\nname = \"Tools\"\n").unwrap();
+        let layout = load_config(&dir.join("smenu.toml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let layout = layout.unwrap();
+        assert_eq!(layout.problems.len(), 1);
+        assert!(layout.problems[0].message.contains("smenu.toml"));
+        // The fragment and the defaults are still there
+        assert!(layout.categories.iter().any(|c| c.name == "Tools"));
+        assert!(!layout.systems.is_empty());
+    }
+
+    #[test]
+    fn duplicate_system() {
+        let system = "version = 2\n[[system]]\nname = \"NES\"\nrom_directory = \"/roms\"\nfile_extensions = [\"nes\"]\n";
+        let mut config = MenuLayout::default();
+        let mut origins = Origins::default();
+        config.merge(parse("/etc/smenu.toml", system), Path::new("/etc/smenu.toml"), &mut origins);
+        config.merge(parse("/etc/smenu.d/nes.toml", system), Path::new("/etc/smenu.d/nes.toml"), &mut origins);
+
+        assert_eq!(config.systems.len(), 1);
+        let problems: Vec<String> = config.problems.iter().map(Problem::to_string).collect();
+        assert_eq!(problems, vec!["/etc/smenu.d/nes.toml: System NES in /etc/smenu.d/nes.toml is already defined in /etc/smenu.toml"]);
+    }
+
+    #[test]
+    fn fragment_order() {
+        let dir = env::temp_dir().join(format!("smenu-test-{}-fragments", std::process::id()));
+        fs::create_dir_all(dir.join("smenu.d")).unwrap();
+        // Written out of order, and a file that isn't a fragment
+        fs::write(dir.join("smenu.d/20-second.toml"), format!("version = 2\n{}", item("Second"))).unwrap();
+        fs::write(dir.join("smenu.d/10-first.toml"), format!("version = 2\n{}", item("First"))).unwrap();
+        fs::write(dir.join("smenu.d/30-ignored.toml.orig"), item("Ignored")).unwrap();
+        fs::write(dir.join("smenu.toml"), format!("version = 2\n{}", item("Main"))).unwrap();
+        let layout = load_config(&dir.join("smenu.toml"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let layout = layout.unwrap();
+        assert!(layout.problems.is_empty());
+        let names: Vec<&str> = layout.items.iter()
+            .map(|i| i.name.as_str())
+            .filter(|n| ["Main", "First", "Second", "Ignored"].contains(n))
+            .collect();
+        assert_eq!(names, ["Main", "First", "Second"]);
+    }
+
+    #[test]
+    fn overlay() {
+        let text = "\
+[[item]]
+name = \"Htop\"
+hide = true
+
+[[item]]
+name = \"Toggle SSH\"
+executable = \"/usr/local/bin/toggle_ssh\"
+
+[[item]]
+name = \"Emulationstation\"
+category = \"Programs\"
+executable = \"/usr/bin/emulationstation\"
+
+[[item]]
+name = \"Launch kmscube\"
+category = \"Tools\"
+hide = true
+
+[[item]]
+name = \"Open shell\"
+category = \"Programs\"
+hide = true
+
+[[system]]
+name = \"SNES\"
+rom_directory = \"/sdcard/SNES\"
+";
+        let mut layout = load_default_config().unwrap();
+        Overlay::parse(&ConfigFile { path: Path::new("config.toml"), text }).unwrap().apply(&mut layout);
+
+        assert!(!layout.items.iter().any(|i| i.name == "Htop"));
+        assert!(!layout.items.iter().any(|i| i.name == "Launch kmscube"));
+        // Only hides an Open shell in Programs, there's none
+        assert!(layout.items.iter().any(|i| i.name == "Open shell" && i.category == "Tools"));
+        let ssh = layout.items.iter().find(|i| i.name == "Toggle SSH").unwrap();
+        assert_eq!(ssh.executable, Path::new("/usr/local/bin/toggle_ssh"));
+        // Everything not overridden stays
+        assert_eq!(ssh.category, "Tools");
+        let snes = layout.systems.iter().find(|s| s.name == "SNES").unwrap();
+        assert_eq!(snes.rom_directory, Path::new("/sdcard/SNES"));
+        assert_eq!(snes.file_extensions.len(), 2);
+
+        assert!(!layout.items.iter().any(|i| i.name == "Emulationstation"));
+        let problems: Vec<String> = layout.problems.iter().map(Problem::to_string).collect();
+        assert_eq!(problems, vec!["New item Emulationstation is missing field uses_wayland"]);
+    }
+
+    /// What load_config does with a main file and no fragments
+    fn over_defaults(text: &str) -> MenuLayout {
+        let config = parse("smenu.toml", text);
+        let mut base = load_default_config().unwrap();
+        base.strip_defaults(&config.defaults);
+        base.layer(config)
+    }
+
+    #[test]
+    fn without_defaults() {
+        let layout = over_defaults(&format!("version = 2\n[defaults]\ninclude = false\n\n[[category]]\nname = \"Tools\"\n\n{}", item("Mine")));
+        assert_eq!(layout.categories, vec![Category { name: "Tools".to_string(), title: None }]);
+        assert_eq!(layout.items.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(), ["Mine"]);
+        assert!(layout.emulators.is_empty());
+        assert!(layout.systems.is_empty());
+    }
+
+    #[test]
+    fn removed_defaults() {
+        let layout = over_defaults("version = 2\n[defaults]\nremove_items = [\"Power Off\"]\nremove_systems = [\"SNES\"]\n");
+        assert!(!layout.items.iter().any(|i| i.name == "Power Off"));
+        assert!(layout.items.iter().any(|i| i.name == "Htop"));
+        assert_eq!(layout.systems.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["NES"]);
+        // Later layers see what was asked for, for the dump
+        assert_eq!(layout.defaults.remove_items, ["Power Off"]);
+    }
+
+    #[test]
+    fn dropped_category_items() {
+        let text = "[[category]]\nname = \"Tools\"\nhide = true\n";
+        let mut hidden = load_default_config().unwrap();
+        Overlay::parse(&ConfigFile { path: Path::new("config.toml"), text }).unwrap().apply(&mut hidden);
+        let removed = over_defaults("version = 2\n[defaults]\nremove_categories = [\"Tools\"]\n");
+
+        for mut layout in [hidden, removed] {
+            assert!(!layout.categories.iter().any(|c| c.name == "Tools"));
+            assert!(!layout.items.iter().any(|i| i.category == "Tools"));
+            layout.check();
+            assert!(!layout.problems.iter().any(|p| p.message.contains("category Tools")));
+        }
+    }
+
+    #[test]
+    fn arranged_tabs() {
+        let names = || vec!["Favorites", "Recent", "Tools", "NES", "SNES", "GBA"];
+        let arrange = |tabs: &Tabs| tabs.arrange(names(), |t| t);
+
+        assert_eq!(arrange(&Tabs::default()), names());
+
+        // Listed ones first, the rest keep their order
+        let mut tabs = Tabs { order: vec!["SNES".to_string(), "Tools".to_string()], ..Tabs::default() };
+        assert_eq!(arrange(&tabs), ["SNES", "Tools", "Favorites", "Recent", "NES", "GBA"]);
+
+        tabs.hidden = vec!["Recent".to_string(), "NES".to_string()];
+        assert_eq!(arrange(&tabs), ["SNES", "Tools", "Favorites", "GBA"]);
+
+        // Rotated so wrapping around past the last tab still goes in order
+        tabs.startup = Some("Favorites".to_string());
+        assert_eq!(arrange(&tabs), ["Favorites", "GBA", "SNES", "Tools"]);
+
+        // A hidden startup tab can't be opened on
+        tabs.startup = Some("NES".to_string());
+        assert_eq!(arrange(&tabs), ["SNES", "Tools", "Favorites", "GBA"]);
+    }
+}
@@ -0,0 +1,387 @@
+use anyhow::{bail, Result, Context};
+use quick_xml::{events::{BytesStart, Event}, Reader};
+use serde::Serialize;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    collections::HashMap,
+};
+
+use libdogd::{log_debug, log_info, LogPriority, log_rust_error};
+
+use crate::archive::{open_in_archive, ArchiveFile};
+use crate::cache::FileCache;
+use crate::hash::{hash_file, hash_reader, Hashes};
+
+/// What a DAT file says about a ROM
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dump {
+    Good,
+    /// Known to be bad, or a known file name with the wrong contents
+    Bad,
+    /// Not in the DAT at all
+    Unknown,
+}
+
+/// A ROM a DAT file lists
+#[derive(Debug, Default)]
+struct DatRom {
+    /// Name of the game it's part of, what the ROM gets listed as
+    title: String,
+    /// File name
+    name: String,
+    size: Option<u64>,
+    crc: Option<u32>,
+    /// Lower case hex
+    sha1: Option<String>,
+    bad: bool,
+}
+
+impl DatRom {
+    fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "name" => self.name = value.to_string(),
+            "size" => self.size = value.parse().ok(),
+            "crc" => self.crc = u32::from_str_radix(value, 16).ok(),
+            "sha1" => self.sha1 = Some(value.to_ascii_lowercase()),
+            "status" | "flags" => self.bad = value == "baddump" || value == "nodump",
+            _ => (),
+        }
+    }
+}
+
+/// `key="value"` pairs of an XML tag
+fn attributes(tag: &BytesStart) -> Result<Vec<(String, String)>> {
+    tag.attributes()
+        .map(|attr| {
+            let attr = attr?;
+            Ok((String::from_utf8_lossy(attr.key.as_ref()).into_owned(), attr.unescape_value()?.into_owned()))
+        })
+        .collect()
+}
+
+/// Logiqx XML, only reading the attributes of <game> and <rom> tags
+fn parse_logiqx(text: &str) -> Result<Vec<DatRom>> {
+    let mut reader = Reader::from_str(text);
+    let mut roms = Vec::new();
+    let mut game = String::new();
+    loop {
+        match reader.read_event().with_context(|| format!("Broken XML at byte {}", reader.buffer_position()))? {
+            Event::Start(tag) | Event::Empty(tag) => match tag.name().as_ref() {
+                b"game" | b"machine" => {
+                    game = attributes(&tag)?.into_iter().find(|(k, _)| k == "name").map(|(_, v)| v).unwrap_or_default();
+                },
+                b"rom" => {
+                    let mut rom = DatRom { title: game.clone(), ..DatRom::default() };
+                    for (key, value) in attributes(&tag)? {
+                        rom.set(&key, &value);
+                    }
+                    roms.push(rom);
+                },
+                _ => (),
+            },
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok(roms)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Open,
+    Close,
+    Word(&'a str),
+}
+
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '(' => {
+                tokens.push(Token::Open);
+                1
+            },
+            ')' => {
+                tokens.push(Token::Close);
+                1
+            },
+            '"' => {
+                let len = rest[1..].find('"').unwrap_or(rest.len() - 1);
+                tokens.push(Token::Word(&rest[1..1 + len]));
+                (len + 2).min(rest.len())
+            },
+            _ => {
+                let len = rest.find(|c: char| c.is_whitespace() || c == '(' || c == ')').unwrap_or(rest.len());
+                tokens.push(Token::Word(&rest[..len]));
+                len
+            },
+        };
+        rest = rest[len..].trim_start();
+    }
+    tokens
+}
+
+/// clrmamepro's `game ( name "..." rom ( name "..." size 123 crc ... ) )` blocks
+fn parse_clrmamepro(text: &str) -> Vec<DatRom> {
+    let tokens = tokenize(text);
+    let mut roms = Vec::new();
+    // Keys of the blocks the current token is in
+    let mut blocks: Vec<&str> = Vec::new();
+    let mut game = String::new();
+    let mut rom: Option<DatRom> = None;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        match (tokens[i], tokens.get(i + 1).copied()) {
+            (Token::Word(key), Some(Token::Open)) => {
+                if key == "rom" && matches!(blocks.last(), Some(&"game" | &"machine" | &"resource")) {
+                    rom = Some(DatRom { title: game.clone(), ..DatRom::default() });
+                }
+                blocks.push(key);
+                i += 2;
+            },
+            (Token::Close, _) => {
+                if blocks.pop() == Some("rom") {
+                    roms.extend(rom.take());
+                }
+                i += 1;
+            },
+            (Token::Word(key), Some(Token::Word(value))) => {
+                match (blocks.last(), rom.as_mut()) {
+                    (Some(&"rom"), Some(rom)) => rom.set(key, value),
+                    (Some(&"game" | &"machine" | &"resource"), _) if key == "name" => game = value.to_string(),
+                    _ => (),
+                }
+                i += 2;
+            },
+            _ => i += 1,
+        }
+    }
+    roms
+}
+
+/// A No-Intro or Redump DAT, indexed for looking ROMs up by their hashes
+pub struct Dat {
+    roms: Vec<DatRom>,
+    by_sha1: HashMap<String, usize>,
+    by_crc: HashMap<u32, Vec<usize>>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Dat {
+    /// Reads a Logiqx XML or clrmamepro DAT
+    pub fn load(path: &Path) -> Result<Dat> {
+        let text = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let dat = Dat::parse(&String::from_utf8_lossy(&text)).with_context(|| format!("Failed to load {}", path.display()))?;
+        log_debug(format!("{} lists {} ROMs", path.display(), dat.roms.len()));
+        Ok(dat)
+    }
+
+    pub fn parse(text: &str) -> Result<Dat> {
+        let roms = match text.trim_start().starts_with('<') {
+            true => parse_logiqx(text)?,
+            false => parse_clrmamepro(text),
+        };
+        if roms.is_empty() {
+            bail!("The DAT doesn't list any ROMs");
+        }
+
+        let mut dat = Dat {
+            by_sha1: HashMap::new(),
+            by_crc: HashMap::new(),
+            by_name: HashMap::new(),
+            roms: Vec::new(),
+        };
+        for (i, rom) in roms.iter().enumerate() {
+            if let Some(sha1) = &rom.sha1 {
+                dat.by_sha1.insert(sha1.clone(), i);
+            }
+            if let Some(crc) = rom.crc {
+                dat.by_crc.entry(crc).or_default().push(i);
+            }
+            dat.by_name.insert(rom.name.to_lowercase(), i);
+        }
+        dat.roms = roms;
+        Ok(dat)
+    }
+
+    /// Looks a ROM up, returning what it is along with the game's title if the DAT knows it.
+    /// `file_name` tells bad dumps of known games from files the DAT has never heard of.
+    pub fn check(&self, hashes: &Hashes, file_name: &OsStr) -> (Dump, Option<&str>) {
+        let found = hashes.sha1.as_ref().and_then(|s| self.by_sha1.get(s))
+            .or_else(|| self.by_crc.get(&hashes.crc)?.iter().find(|&&i| self.roms[i].size.is_none_or(|s| s == hashes.size)));
+
+        match found {
+            Some(&i) => {
+                let rom = &self.roms[i];
+                (if rom.bad { Dump::Bad } else { Dump::Good }, Some(&rom.title))
+            },
+            None => match self.by_name.get(&file_name.to_string_lossy().to_lowercase()) {
+                Some(&i) => (Dump::Bad, Some(&self.roms[i].title)),
+                None => (Dump::Unknown, None),
+            },
+        }
+    }
+}
+
+/// When ROMs that aren't in the hash cache get hashed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hashing {
+    /// Right away, for the command line
+    Now,
+    /// Later, by `hash_all` on a thread of its own. Until then they're left unverified so the
+    /// menu doesn't wait on reading every new ROM.
+    Cached,
+}
+
+/// Hashes of every ROM verified so far, so only new and changed files get read on a rescan
+pub struct HashCache {
+    cache: FileCache<Hashes>,
+    /// How many files were hashed rather than found in the cache, not counting ones that failed
+    hashed: usize,
+}
+
+impl HashCache {
+    pub fn load() -> HashCache {
+        HashCache {
+            cache: FileCache::load("hashes"),
+            hashed: 0,
+        }
+    }
+
+    /// Hashes of the file at `path`, `progress` being how far into the scan it is for the log
+    pub fn hash(&mut self, path: &Path, progress: (usize, usize)) -> Option<Hashes> {
+        self.cache.get(path, path, || {
+            log_info(format!("Hashing {} ({}/{})", path.display(), progress.0, progress.1));
+            let hashes = hash_file(path).map_err(|e| log_rust_error(&*e, "Failed to hash a ROM", LogPriority::Error)).ok();
+            self.hashed += usize::from(hashes.is_some());
+            hashes
+        })
+    }
+
+    /// Same for a file in an archive, which only gets unpacked when its stored CRC won't do
+    pub fn hash_in_archive(&mut self, archive: &Path, file: &ArchiveFile, progress: (usize, usize)) -> Option<Hashes> {
+        self.cache.get(&archive.join(&file.path), archive, || {
+            log_info(format!("Hashing {} in {} ({}/{})", file.path.display(), archive.display(), progress.0, progress.1));
+            let hashes = open_in_archive(archive, file)
+                .and_then(|reader| hash_reader(reader, file.size, &file.path, file.crc).context("Failed to unpack"))
+                .map_err(|e| log_rust_error(&*e, "Failed to hash a ROM", LogPriority::Error))
+                .ok();
+            self.hashed += usize::from(hashes.is_some());
+            hashes
+        })
+    }
+
+    /// What's cached for the file at `path`, or for `inner` in it, without reading anything
+    pub fn cached(&mut self, path: &Path, inner: Option<&ArchiveFile>) -> Option<Hashes> {
+        let key = inner.map_or_else(|| path.to_path_buf(), |i| path.join(&i.path));
+        self.cache.get(&key, path, || None)
+    }
+
+    pub fn save(&self) {
+        self.cache.save();
+    }
+}
+
+/// Hashes whatever in `roms` isn't cached yet and saves the cache, returning whether anything
+/// new got hashed. Failures don't count, the reload that follows would only try them again.
+/// `roms` has to hold every ROM there is, the rest drop out of the cache.
+pub fn hash_all(roms: &[(PathBuf, Option<ArchiveFile>)]) -> bool {
+    let mut cache = HashCache::load();
+    let total = roms.len();
+    for (i, (path, inner)) in roms.iter().enumerate() {
+        match inner {
+            Some(inner) => cache.hash_in_archive(path, inner, (i + 1, total)),
+            None => cache.hash(path, (i + 1, total)),
+        };
+    }
+    cache.save();
+    cache.hashed > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGIQX: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/dtds/1.3/datafile.dtd">
+<datafile>
+	<header>
+		<name>Nintendo - Nintendo Entertainment System</name>
+	</header>
+	<game name="Tom &amp; Jerry (USA)">
+		<description>Tom &amp; Jerry (USA)</description>
+		<rom name="Tom &amp; Jerry (USA).nes" size="32" crc="af267503" sha1="3C2D63CD8D2C2E5FE8489A84C3B7576710649118"/>
+	</game>
+	<game name="Broken (Europe)">
+		<rom name="Broken (Europe).nes" size="16" crc="12345678" status="baddump"/>
+	</game>
+</datafile>
+"#;
+
+    const CLRMAMEPRO: &str = r#"clrmamepro (
+	name "Nintendo - Nintendo Entertainment System"
+	version 20240101
+)
+
+game (
+	name "Tom & Jerry (USA)"
+	description "Tom & Jerry (USA)"
+	rom ( name "Tom & Jerry (USA).nes" size 32 crc AF267503 sha1 3C2D63CD8D2C2E5FE8489A84C3B7576710649118 )
+)
+
+game (
+	name "Broken (Europe)"
+	rom ( name "Broken (Europe).nes" size 16 crc 12345678 flags baddump )
+)
+"#;
+
+    fn check_roms(roms: &[DatRom]) {
+        assert_eq!(roms.len(), 2);
+        assert_eq!(roms[0].title, "Tom & Jerry (USA)");
+        assert_eq!(roms[0].name, "Tom & Jerry (USA).nes");
+        assert_eq!(roms[0].size, Some(32));
+        assert_eq!(roms[0].crc, Some(0xAF26_7503));
+        assert_eq!(roms[0].sha1.as_deref(), Some("3c2d63cd8d2c2e5fe8489a84c3b7576710649118"));
+        assert!(!roms[0].bad);
+        assert_eq!(roms[1].title, "Broken (Europe)");
+        assert_eq!(roms[1].sha1, None);
+        assert!(roms[1].bad);
+    }
+
+    #[test]
+    fn logiqx() {
+        check_roms(&parse_logiqx(LOGIQX).unwrap());
+    }
+
+    #[test]
+    fn clrmamepro() {
+        check_roms(&parse_clrmamepro(CLRMAMEPRO));
+    }
+
+    #[test]
+    fn lookups() {
+        let dat = Dat::parse(LOGIQX).unwrap();
+        let name = OsStr::new("whatever.nes");
+        let by_sha1 = Hashes { size: 32, crc: 0, sha1: Some("3c2d63cd8d2c2e5fe8489a84c3b7576710649118".to_string()) };
+        assert_eq!(dat.check(&by_sha1, name), (Dump::Good, Some("Tom & Jerry (USA)")));
+
+        let by_crc = Hashes { size: 32, crc: 0xAF26_7503, sha1: None };
+        assert_eq!(dat.check(&by_crc, name), (Dump::Good, Some("Tom & Jerry (USA)")));
+        let wrong_size = Hashes { size: 33, ..by_crc };
+        assert_eq!(dat.check(&wrong_size, name), (Dump::Unknown, None));
+
+        let bad = Hashes { size: 16, crc: 0x1234_5678, sha1: None };
+        assert_eq!(dat.check(&bad, name), (Dump::Bad, Some("Broken (Europe)")));
+
+        let changed = Hashes { size: 32, crc: 1, sha1: Some("0".repeat(40)) };
+        assert_eq!(dat.check(&changed, OsStr::new("TOM & JERRY (USA).NES")), (Dump::Bad, Some("Tom & Jerry (USA)")));
+        assert_eq!(dat.check(&changed, name), (Dump::Unknown, None));
+
+        assert!(Dat::parse("nothing here").is_err());
+    }
+}
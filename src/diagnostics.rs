@@ -0,0 +1,366 @@
+use serde::de::{Deserialize, DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use anyhow::{Result, Context};
+use std::{
+    env,
+    fmt,
+    fs,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    os::unix::fs::PermissionsExt,
+};
+
+use libdogd::log_info;
+
+use crate::config::MenuLayout;
+use crate::menu::{FAVORITES_TAB, RECENT_TAB, SEARCH_TAB};
+use crate::migrate::{migrate, CURRENT_VERSION};
+
+/// Something wrong with the config that doesn't stop the rest of it from loading
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub source: Option<PathBuf>,
+    /// 1-based line and column in `source`
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl Problem {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            source: None,
+            position: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.source, self.position) {
+            (Some(source), Some((line, column))) => write!(f, "{}:{}:{}: {}", source.display(), line, column, self.message),
+            (Some(source), None) => write!(f, "{}: {}", source.display(), self.message),
+            (None, _) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Deserializes only `key` out of a whole config file, or only its `index`th element if it's an
+/// array, so errors in it come from the TOML parser along with where they are
+struct Section<'a, T> {
+    key: &'a str,
+    index: Option<usize>,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: DeserializeOwned> DeserializeSeed<'de> for Section<'_, T> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, T: DeserializeOwned> Visitor<'de> for Section<'_, T> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a table")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match self.index {
+                _ if key != self.key => { map.next_value::<IgnoredAny>()?; },
+                None => { map.next_value::<T>()?; },
+                Some(index) => map.next_value_seed(Element::<T> { index, marker: PhantomData })?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Element<T> {
+    index: usize,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: DeserializeOwned> DeserializeSeed<'de> for Element<T> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, T: DeserializeOwned> Visitor<'de> for Element<T> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        for i in 0.. {
+            let more = match i == self.index {
+                true => seq.next_element::<T>()?.is_some(),
+                false => seq.next_element::<IgnoredAny>()?.is_some(),
+            };
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Refuses any value, for finding out where one is
+struct Reject;
+
+impl<'de> Deserialize<'de> for Reject {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Reject, D::Error> {
+        deserializer.deserialize_any(Reject)
+    }
+}
+
+impl<'de> Visitor<'de> for Reject {
+    type Value = Reject;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nothing")
+    }
+}
+
+/// A config file being parsed leniently: every entry of a `[[table]]` array is deserialized
+/// on its own, so one broken entry gets reported and skipped instead of taking the whole file down
+pub struct ConfigFile<'a> {
+    pub path: &'a Path,
+    pub text: &'a str,
+}
+
+impl ConfigFile<'_> {
+    pub fn problem(&self, position: Option<(usize, usize)>, message: impl Into<String>) -> Problem {
+        Problem {
+            source: Some(self.path.to_path_buf()),
+            position,
+            message: message.into(),
+        }
+    }
+
+    /// Parses the file and brings it up to the current config version
+    pub fn table(&self, problems: &mut Vec<Problem>) -> Result<toml::value::Table> {
+        let mut table: toml::value::Table = toml::from_str(self.text)
+            .with_context(|| format!("Failed to deserialize config file {}", self.path.display()))?;
+
+        match migrate(&mut table) {
+            Ok(version) if version < CURRENT_VERSION => {
+                log_info(format!("{} is at config version {}, run `smenu migrate` to upgrade it to {}", self.path.display(), version, CURRENT_VERSION));
+            },
+            Ok(_) => (),
+            Err(e) => problems.push(self.problem(None, format!("{:#}", e))),
+        }
+        table.remove("version");
+        Ok(table)
+    }
+
+    /// Line and column of what's wrong with `key`, or with its `index`th element, according to
+    /// the TOML parser. The lenient parsing works on already parsed values, which don't know
+    /// where they came from, so the part that failed gets parsed again from the text.
+    fn locate(&self, seed: impl for<'de> DeserializeSeed<'de>) -> Option<(usize, usize)> {
+        let e = seed.deserialize(&mut toml::Deserializer::new(self.text)).err()?;
+        e.line_col().map(|(line, column)| (line + 1, column + 1))
+    }
+
+    fn locate_section<T: DeserializeOwned>(&self, key: &str, index: Option<usize>) -> Option<(usize, usize)> {
+        self.locate(Section::<T> { key, index, marker: PhantomData })
+    }
+
+    pub fn parse_table<T: DeserializeOwned + Default>(&self, table: &mut toml::value::Table, key: &str, problems: &mut Vec<Problem>) -> T {
+        let Some(value) = table.remove(key) else {
+            return T::default();
+        };
+        match value.try_into() {
+            Ok(v) => v,
+            Err(e) => {
+                problems.push(self.problem(self.locate_section::<T>(key, None), format!("Ignoring [{}]: {}", key, e)));
+                T::default()
+            },
+        }
+    }
+
+    pub fn parse_array<T: DeserializeOwned>(&self, table: &mut toml::value::Table, key: &str, problems: &mut Vec<Problem>) -> Vec<T> {
+        let Some(value) = table.remove(key) else {
+            return Vec::new();
+        };
+        let toml::Value::Array(elements) = value else {
+            let position = self.locate(Section::<Reject> { key, index: None, marker: PhantomData });
+            problems.push(self.problem(position, format!("{} has to be an array of tables, like [[{}]]", key, key)));
+            return Vec::new();
+        };
+
+        let mut ret = Vec::new();
+        for (i, element) in elements.into_iter().enumerate() {
+            let name = element.get("name")
+                .and_then(|n| n.as_str())
+                .map(|n| format!(" \"{}\"", n))
+                .unwrap_or_default();
+            match element.try_into() {
+                Ok(e) => ret.push(e),
+                Err(e) => problems.push(self.problem(self.locate_section::<T>(key, Some(i)), format!("Skipping {} #{}{}: {}", key, i + 1, name, e))),
+            }
+        }
+        ret
+    }
+
+    /// Reports whatever is left in `table` after all known keys were taken out of it
+    pub fn unknown_fields(&self, table: toml::value::Table, problems: &mut Vec<Problem>) {
+        for key in table.keys() {
+            let position = self.locate(Section::<Reject> { key, index: None, marker: PhantomData });
+            problems.push(self.problem(position, format!("Unknown field {}", key)));
+        }
+    }
+}
+
+/// Whether `p` can be run, looking it up in `$PATH` like `Command` does if it's a bare name
+fn is_executable(p: &Path) -> bool {
+    let check = |p: &Path| fs::metadata(p).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+    if p.components().count() > 1 {
+        return check(p);
+    }
+    env::var_os("PATH").is_some_and(|paths| env::split_paths(&paths).any(|dir| check(&dir.join(p))))
+}
+
+impl MenuLayout {
+    /// Looks for things that parse fine but won't work once the menu is up. Whether executables
+    /// and directories exist is only checked for what the config files set up, the built-in
+    /// defaults don't have to fit every device.
+    pub fn check(&mut self) {
+        let mut problems = Vec::new();
+
+        for item in self.items.iter() {
+            if !self.categories.iter().any(|c| c.name == item.category) {
+                problems.push(Problem::new(format!("Item {} is in category {}, which isn't defined", item.name, item.category)));
+            }
+            if !item.builtin && !is_executable(&item.executable) {
+                problems.push(Problem::new(format!("Executable {} of item {} is missing or not executable", item.executable.display(), item.name)));
+            }
+        }
+
+        for emulator in self.emulators.iter() {
+            if !emulator.builtin && !is_executable(&emulator.executable) {
+                problems.push(Problem::new(format!("Emulator {} is missing or not executable", emulator.executable.display())));
+            }
+        }
+
+        for system in self.systems.iter() {
+            if !self.emulators.iter().any(|e| e.systems.contains(&system.name)) {
+                problems.push(Problem::new(format!("System {} has no emulator", system.name)));
+            }
+            if system.builtin {
+                continue;
+            }
+            if let Err(e) = fs::read_dir(&system.rom_directory) {
+                problems.push(Problem::new(format!("ROM directory {} of system {} is unreachable: {}", system.rom_directory.display(), system.name, e)));
+            }
+            if let Some(dat_file) = &system.dat_file {
+                if let Err(e) = fs::metadata(dat_file) {
+                    problems.push(Problem::new(format!("DAT file {} of system {} is unreadable: {}", dat_file.display(), system.name, e)));
+                }
+            }
+        }
+
+        for name in self.tabs.names().filter(|n| ![SEARCH_TAB, FAVORITES_TAB, RECENT_TAB].contains(&n.as_str())) {
+            if !self.categories.iter().any(|c| &c.name == name) && !self.systems.iter().any(|s| &s.name == name) {
+                problems.push(Problem::new(format!("[tabs] refers to {}, which is neither a category nor a system", name)));
+            }
+        }
+
+        // Nothing but the warnings would be left to show
+        let mut tab_names = self.categories.iter().map(|c| c.name.as_str())
+            .chain(self.items.iter().map(|i| i.category.as_str()))
+            .chain(self.systems.iter().map(|s| s.name.as_str()))
+            .chain([SEARCH_TAB, FAVORITES_TAB, RECENT_TAB]);
+        if tab_names.all(|n| self.tabs.hidden.iter().any(|h| h == n)) {
+            problems.push(Problem::new("[tabs] hides every tab"));
+        }
+
+        self.problems.extend(problems);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load_config;
+
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Entry {
+        name: String,
+        size: u32,
+    }
+
+    const TEXT: &str = "\
+verbose = true
+
+[[entry]]
+name = \"first\"
+size = 1
+
+[[entry]]
+name = \"second\"
+size = \"big\"
+
+[section]
+thing = 1
+";
+
+    /// Problems with everything in `text`, in the order they're found
+    fn parse(text: &str) -> Vec<Problem> {
+        let file = ConfigFile { path: Path::new("test.toml"), text };
+        let mut problems = Vec::new();
+        let mut table = file.table(&mut problems).unwrap();
+        let entries: Vec<Entry> = file.parse_array(&mut table, "entry", &mut problems);
+        assert_eq!(entries.len(), 1);
+        file.parse_array::<Entry>(&mut table, "section", &mut problems);
+        file.unknown_fields(table, &mut problems);
+        problems
+    }
+
+    #[test]
+    fn positions() {
+        let positions: Vec<_> = parse(TEXT).iter().map(|p| p.position).collect();
+        // The broken value, the plain table and the unknown key's value
+        assert_eq!(positions, vec![Some((9, 8)), Some((11, 1)), Some((1, 11))]);
+    }
+
+    #[test]
+    fn every_tab_hidden() {
+        let mut layout = MenuLayout::default();
+        layout.tabs.hidden = vec![SEARCH_TAB.to_string(), FAVORITES_TAB.to_string()];
+        layout.check();
+        assert!(layout.problems.is_empty());
+
+        layout.tabs.hidden.push(RECENT_TAB.to_string());
+        layout.check();
+        assert_eq!(layout.problems.iter().map(Problem::to_string).collect::<Vec<_>>(), vec!["[tabs] hides every tab"]);
+    }
+
+    #[test]
+    fn defaults_not_checked() {
+        let dir = env::temp_dir().join(format!("smenu-test-{}-defaults", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("smenu.toml");
+        let mut layout = load_config(&config).unwrap();
+        layout.check();
+        assert!(layout.problems.is_empty());
+
+        fs::write(&config, "[[system]]\nname = \"SNES\"\nrom_directory = \"/nonexistent/SNES\"\nfile_extensions = [\"sfc\"]\n").unwrap();
+        let mut layout = load_config(&config).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        layout.check();
+        let problems: Vec<String> = layout.problems.iter().map(Problem::to_string).collect();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("ROM directory /nonexistent/SNES of system SNES is unreachable"));
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+    collections::{BTreeMap, HashSet},
+};
+
+use libdogd::{log_debug, LogPriority, log_rust_error};
+
+use crate::menu::Rom;
+use crate::state::write_atomic;
+
+/// Paths compared the way FAT file systems and the tools writing cue sheets do, ignoring case
+fn path_key(p: &Path) -> Vec<u8> {
+    p.as_os_str().as_bytes().to_ascii_lowercase()
+}
+
+fn extension_is(p: &Path, ext: &str) -> bool {
+    p.extension().is_some_and(|e| e.as_bytes().eq_ignore_ascii_case(ext.as_bytes()))
+}
+
+/// The file name at the start of `s`, in quotes if it has spaces
+fn file_name_field(s: &[u8]) -> &[u8] {
+    let s = s.trim_ascii_start();
+    match s.strip_prefix(b"\"") {
+        Some(quoted) => quoted.split(|&b| b == b'"').next().unwrap_or(quoted),
+        None => s.split(|b| b.is_ascii_whitespace()).next().unwrap_or(s),
+    }
+}
+
+fn lines(text: &[u8]) -> impl Iterator<Item = &[u8]> {
+    text.split(|&b| b == b'\n').map(|l| l.trim_ascii()).filter(|l| !l.is_empty())
+}
+
+/// Files a disc sheet refers to, as written in it
+fn referenced_files(sheet: &Path, text: &[u8]) -> Vec<PathBuf> {
+    let mut names: Vec<&[u8]> = Vec::new();
+    if extension_is(sheet, "cue") {
+        // FILE "Track 01.bin" BINARY
+        for line in lines(text) {
+            if line.len() > 4 && line[..4].eq_ignore_ascii_case(b"FILE") && line[4].is_ascii_whitespace() {
+                names.push(file_name_field(&line[4..]));
+            }
+        }
+    } else if extension_is(sheet, "gdi") {
+        // A track count, then: number, start sector, type, sector size, file name, offset
+        for line in lines(text).skip(1) {
+            let mut rest = line;
+            for _ in 0..4 {
+                rest = rest.trim_ascii_start();
+                let end = rest.iter().position(|b| b.is_ascii_whitespace()).unwrap_or(rest.len());
+                rest = &rest[end..];
+            }
+            names.push(file_name_field(rest));
+        }
+    } else if extension_is(sheet, "m3u") {
+        names.extend(lines(text).filter(|l| !l.starts_with(b"#")));
+    }
+
+    let dir = sheet.parent().unwrap_or(Path::new(""));
+    names.into_iter()
+        .filter(|n| !n.is_empty())
+        .map(|n| dir.join(OsStr::from_bytes(n)))
+        .collect()
+}
+
+/// Drops the track files cue, gdi and ccd sheets use and the discs m3u playlists list, so
+/// only the sheet or playlist is left for every game
+pub fn hide_tracks(files: &mut Vec<(PathBuf, SystemTime)>) {
+    let mut hidden = HashSet::new();
+    for (path, _) in files.iter() {
+        if extension_is(path, "ccd") {
+            // CloneCD images go by the same name as the sheet
+            hidden.insert(path_key(&path.with_extension("img")));
+            hidden.insert(path_key(&path.with_extension("sub")));
+            continue;
+        }
+        if !["cue", "gdi", "m3u"].iter().any(|ext| extension_is(path, ext)) {
+            continue;
+        }
+        match fs::read(path) {
+            Ok(text) => hidden.extend(referenced_files(path, &text).iter().map(|p| path_key(p))),
+            Err(e) => log_rust_error(&e, format!("Failed to read {}", path.display()), LogPriority::Error),
+        }
+    }
+    files.retain(|(path, _)| !hidden.contains(&path_key(path)));
+}
+
+/// Splits "Game (USA) (Disc 2)" into "Game (USA)" and 2, "Disk" and "(Disc 2 of 3)" work too
+fn disc_number(name: &str) -> Option<(String, u32)> {
+    let lower = name.to_ascii_lowercase();
+    for tag in ["(disc ", "(disk "] {
+        let Some(start) = lower.find(tag) else { continue };
+        let rest = &lower[start + tag.len()..];
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let Ok(number) = rest[..digits].parse() else { continue };
+        let Some(end) = rest.find(')') else { continue };
+        let between = rest[digits..end].trim();
+        if !between.is_empty() && between.strip_prefix("of ").is_none_or(|total| total.trim().parse::<u32>().is_err()) {
+            continue;
+        }
+
+        let base = format!("{} {}", name[..start].trim_end(), name[start + tag.len() + end + 1..].trim_start());
+        return Some((base.trim().to_string(), number));
+    }
+    None
+}
+
+/// Merges the discs of every game into one ROM launching a playlist of them, written into
+/// `m3u_dir` rather than next to the discs since ROM directories are often read only. Only
+/// discs in the same directory are grouped, whether or not subfolders get flattened.
+pub fn group_discs(roms: Vec<Rom>, m3u_dir: &Path) -> Vec<Rom> {
+    let mut ret = Vec::new();
+    // Ordered so the ROMs come out the same on every scan, which the dumped config relies on
+    let mut games: BTreeMap<(PathBuf, String), Vec<(u32, Rom)>> = BTreeMap::new();
+    for rom in roms {
+        // Discs in archives get unpacked one at a time, a playlist can't point into them
+        if rom.inner.is_some() {
+            ret.push(rom);
+            continue;
+        }
+        match disc_number(&rom.name) {
+            Some((base, number)) => {
+                let dir = rom.key.parent().map(Path::to_path_buf).unwrap_or_default();
+                games.entry((dir, base)).or_default().push((number, rom));
+            },
+            None => ret.push(rom),
+        }
+    }
+
+    for ((dir, name), mut discs) in games {
+        if discs.len() == 1 {
+            ret.extend(discs.into_iter().map(|(_, rom)| rom));
+            continue;
+        }
+        discs.sort_by_key(|(number, _)| *number);
+
+        let mut playlist = Vec::new();
+        for (_, disc) in discs.iter() {
+            playlist.extend_from_slice(disc.path.as_os_str().as_bytes());
+            playlist.push(b'\n');
+        }
+        let path = m3u_dir.join(&dir).join(format!("{}.m3u", name));
+        // Left alone when nothing changed, this runs on every scan
+        if fs::read(&path).ok().as_deref() != Some(&playlist[..]) {
+            log_debug(format!("Writing {}", path.display()));
+            if let Err(e) = write_atomic(&path, &playlist) {
+                log_rust_error(&*e, format!("Failed to write a playlist for {}, listing its discs separately", name), LogPriority::Error);
+                ret.extend(discs.into_iter().map(|(_, rom)| rom));
+                continue;
+            }
+        }
+
+        let (_, first) = discs.swap_remove(0);
+        let added = discs.iter().map(|(_, d)| d.added).fold(first.added, |a, b| a.max(b));
+        ret.push(Rom {
+            name,
+            path,
+            added,
+            ..first
+        });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn paths(v: &[&str]) -> Vec<PathBuf> {
+        v.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn disc_numbers() {
+        assert_eq!(disc_number("Game (USA) (Disc 2)"), Some(("Game (USA)".into(), 2)));
+        assert_eq!(disc_number("Game (Disc 1 of 3) (USA)"), Some(("Game (USA)".into(), 1)));
+        assert_eq!(disc_number("game (DISK 3)"), Some(("game".into(), 3)));
+        assert_eq!(disc_number("Game (Disc A)"), None);
+        assert_eq!(disc_number("Game (Disc 1 Bonus)"), None);
+        assert_eq!(disc_number("Game (Disc 1 of many)"), None);
+        assert_eq!(disc_number("Game (USA)"), None);
+    }
+
+    #[test]
+    fn cue() {
+        let text = b"FILE \"Track 01.bin\" BINARY\r\n  TRACK 01 MODE1/2352\r\nfile track02.bin BINARY\r\n  TRACK 02 AUDIO\r\n";
+        assert_eq!(referenced_files(Path::new("/roms/Game.cue"), text), paths(&["/roms/Track 01.bin", "/roms/track02.bin"]));
+    }
+
+    #[test]
+    fn gdi() {
+        let text = b"3\n1 0 4 2352 track01.bin 0\n2 756 0 2352 \"track 02.raw\" 0\n3 45000 4 2352 track03.bin 0\n";
+        assert_eq!(referenced_files(Path::new("/roms/Game.GDI"), text), paths(&["/roms/track01.bin", "/roms/track 02.raw", "/roms/track03.bin"]));
+    }
+
+    #[test]
+    fn m3u() {
+        let text = b"#EXTM3U\nGame (Disc 1).cue\n\nGame (Disc 2).cue\n";
+        assert_eq!(referenced_files(Path::new("Game.m3u"), text), paths(&["Game (Disc 1).cue", "Game (Disc 2).cue"]));
+        assert!(referenced_files(Path::new("Game.txt"), text).is_empty());
+    }
+
+    #[test]
+    fn tracks_hidden() {
+        let dir = env::temp_dir().join(format!("smenu-test-{}-discs", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Sheets are often written by tools that don't keep the case of the file names
+        fs::write(dir.join("Game.cue"), "FILE \"GAME (TRACK 1).BIN\" BINARY\n").unwrap();
+        let names = ["Game.cue", "Game (Track 1).bin", "Other.bin", "Clone.ccd", "Clone.img", "Clone.sub"];
+        let mut files: Vec<(PathBuf, SystemTime)> = names.iter().map(|n| (dir.join(n), SystemTime::UNIX_EPOCH)).collect();
+        hide_tracks(&mut files);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let left: Vec<PathBuf> = files.into_iter().map(|(p, _)| p).collect();
+        assert_eq!(left, vec![dir.join("Game.cue"), dir.join("Other.bin"), dir.join("Clone.ccd")]);
+    }
+
+    #[test]
+    fn flattened_regions() {
+        let roms_dir = Path::new("/roms/PSX");
+        let m3u_dir = env::temp_dir().join(format!("smenu-test-{}-playlists", std::process::id()));
+        // Flattened, so every ROM is shown in the tab's root
+        let rom = |key: &str| Rom {
+            name: Path::new(key).file_stem().unwrap().to_string_lossy().into_owned(),
+            path: roms_dir.join(key),
+            key: PathBuf::from(key),
+            folder: PathBuf::new(),
+            inner: None,
+            system: 0,
+            emulator: 0,
+            added: SystemTime::UNIX_EPOCH,
+            dump: None,
+        };
+        let keys = ["USA/Game (Disc 1).cue", "Europe/Game (Disc 2).cue", "USA/Game (Disc 2).cue", "Europe/Game (Disc 1).cue"];
+        let mut grouped = group_discs(keys.iter().map(|k| rom(k)).collect(), &m3u_dir);
+        grouped.sort_by(|a, b| a.path.cmp(&b.path));
+        let usa = fs::read_to_string(m3u_dir.join("USA/Game.m3u")).unwrap();
+        let europe = fs::read_to_string(m3u_dir.join("Europe/Game.m3u")).unwrap();
+        fs::remove_dir_all(&m3u_dir).unwrap();
+
+        let grouped: Vec<(String, PathBuf, PathBuf)> = grouped.into_iter().map(|r| (r.name, r.path, r.key)).collect();
+        assert_eq!(grouped, vec![
+            ("Game".to_string(), m3u_dir.join("Europe/Game.m3u"), PathBuf::from("Europe/Game (Disc 1).cue")),
+            ("Game".to_string(), m3u_dir.join("USA/Game.m3u"), PathBuf::from("USA/Game (Disc 1).cue")),
+        ]);
+        assert_eq!(usa, "/roms/PSX/USA/Game (Disc 1).cue\n/roms/PSX/USA/Game (Disc 2).cue\n");
+        assert_eq!(europe, "/roms/PSX/Europe/Game (Disc 1).cue\n/roms/PSX/Europe/Game (Disc 2).cue\n");
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+/// Length of the header copiers and emulators put in front of some ROMs, which DATs leave out
+fn header_len(start: &[u8], file_size: u64, path: &Path) -> usize {
+    let has_ext = |exts: &[&str]| path.extension().is_some_and(|e| exts.iter().any(|x| e.eq_ignore_ascii_case(x)));
+    if start.starts_with(b"NES\x1A") || start.starts_with(b"FDS\x1A") {
+        16
+    } else if start.get(1..10) == Some(&b"ATARI7800"[..]) {
+        128
+    } else if start.starts_with(b"LYNX\0") {
+        64
+    } else if file_size % 1024 == 512 && has_ext(&["smc", "sfc", "swc", "fig"]) {
+        512
+    } else {
+        0
+    }
+}
+
+/// Hashes of a ROM's data, without any header
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hashes {
+    pub size: u64,
+    pub crc: u32,
+    /// Lower case hex, not known for headerless files inside archives
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+}
+
+/// Fills as much of `buf` as there is data for, a single read can stop short of a header
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Hashes a ROM of `file_size` bytes read from `reader`, with `path` being its name for
+/// recognizing headers. `known_crc` is a CRC of the whole file from somewhere else, like an
+/// archive's listing. It's taken as it is when there's no header, which saves reading the rest.
+pub fn hash_reader(mut reader: impl Read, file_size: u64, path: &Path, known_crc: Option<u32>) -> io::Result<Hashes> {
+    let mut buf = vec![0; 64 * 1024];
+    let n = read_full(&mut reader, &mut buf)?;
+    let header = header_len(&buf[..n], file_size, path).min(n);
+    if let (0, Some(crc)) = (header, known_crc) {
+        return Ok(Hashes {
+            size: file_size,
+            crc,
+            sha1: None,
+        });
+    }
+
+    let mut crc = crc32fast::Hasher::new();
+    let mut sha1 = sha1_smol::Sha1::new();
+    let mut data = &buf[header..n];
+    let mut size = 0;
+    while !data.is_empty() {
+        crc.update(data);
+        sha1.update(data);
+        size += data.len() as u64;
+        let n = read_full(&mut reader, &mut buf)?;
+        data = &buf[..n];
+    }
+
+    Ok(Hashes {
+        size,
+        crc: crc.finalize(),
+        sha1: Some(sha1.digest().to_string()),
+    })
+}
+
+pub fn hash_file(path: &Path) -> Result<Hashes> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let file_size = file.metadata()?.len();
+    hash_reader(file, file_size, path, None).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(data: &[u8]) -> Hashes {
+        hash_reader(data, data.len() as u64, Path::new("Game.bin"), None).unwrap()
+    }
+
+    #[test]
+    fn known_answers() {
+        assert_eq!(hashes(b"123456789").crc, 0xCBF4_3926);
+        assert_eq!(hashes(b"abc").sha1.as_deref(), Some("a9993e364706816aba3e25717850c26c9cd0d89d"));
+        // Takes more than one read
+        let million = hashes(&[b'a'; 1_000_000]);
+        assert_eq!(million.size, 1_000_000);
+        assert_eq!(million.sha1.as_deref(), Some("34aa973cd4c4daa4f61eeb2bdbad27316534016f"));
+    }
+
+    fn nes_rom() -> Vec<u8> {
+        let mut rom = b"NES\x1A".to_vec();
+        rom.extend([0; 12]);
+        rom.extend(b"ROMDATA!".repeat(4));
+        rom
+    }
+
+    #[test]
+    fn header_is_skipped() {
+        let rom = nes_rom();
+        let hashes = hash_reader(&rom[..], rom.len() as u64, Path::new("Game.nes"), None).unwrap();
+        assert_eq!(hashes, Hashes {
+            size: 32,
+            crc: 0xAF26_7503,
+            sha1: Some("3c2d63cd8d2c2e5fe8489a84c3b7576710649118".to_string()),
+        });
+    }
+
+    #[test]
+    fn known_crc_only_without_header() {
+        // A stored CRC covers the header, so it's no use for headered ROMs
+        let rom = nes_rom();
+        let hashes = hash_reader(&rom[..], rom.len() as u64, Path::new("Game.nes"), Some(0x8093_3C5D)).unwrap();
+        assert_eq!(hashes.crc, 0xAF26_7503);
+        assert!(hashes.sha1.is_some());
+
+        let hashes = hash_reader(&b"hi\n"[..], 3, Path::new("readme.txt"), Some(0xED6F_7A7A)).unwrap();
+        assert_eq!(hashes, Hashes { size: 3, crc: 0xED6F_7A7A, sha1: None });
+    }
+}
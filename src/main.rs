@@ -1,4 +1,19 @@
-use sgui::layout::Layout;
+mod archive;
+mod cache;
+mod config;
+mod dat;
+mod diagnostics;
+mod discs;
+mod hash;
+mod menu;
+mod migrate;
+mod roms;
+mod search;
+mod sort;
+mod state;
+mod vars;
+mod watch;
+
 use sgui::Gui;
 use sgui::GuiEvent;
 
@@ -6,165 +21,40 @@ use nix::{
     ioctl_write_int_bad,
     sys::signal::Signal,
 };
-use serde::{Serialize, Deserialize};
 use anyhow::{anyhow, Result, Context};
 use std::{
     env,
-    process::{Command, Child, Stdio},
-    fs::{self, File, OpenOptions},
-    thread,
+    process::{self, Command, Child, Stdio},
+    fs::{File, OpenOptions},
+    thread::{self, JoinHandle},
     io::{
         Read, BufReader, BufRead,
         Write,
     },
-    path::{Path, PathBuf},
+    path::PathBuf,
     os::unix::{
         io::AsRawFd,
         process::ExitStatusExt,
     },
-    collections::{HashSet, HashMap},
 };
 
+use archive::Extracted;
+use config::{MenuEntry, MenuLayout, load_config, load_overlay, user_overlay_path, user_overlay_paths, drop_in_dir, drop_in_fragments};
+use dat::{hash_all, Hashing};
+use diagnostics::Problem;
+use menu::{Action, Menu, NodePath, RomTab};
+use migrate::{migrate_file, CURRENT_VERSION};
+use state::{State, default_state_path};
+use vars::Variables;
+use watch::{Watcher, reload_on_sighup, request_reload};
 use libdogd::{log_debug, log_info, log_error, log_critical, LogPriority, post_log, log_rust_error};
 
+static DEFAULT_CONFIG_PATH: &str = "/etc/smenu.toml";
+
 static SMENU_TTY: i32 = 1;
 static WESTON_TTY: i32 = 2;
 static TERMINAL_TTY: i32 = 3;
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Category {
-    Tools,
-    Programs,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct MenuEntry {
-    name: String,
-    category: Category,
-    uses_wayland: bool,
-    executable: PathBuf,
-    #[serde(default)]
-    args: Vec<String>,
-    #[serde(default)]
-    env: Vec<(String, String)>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Emulator {
-    executable: PathBuf,
-    #[serde(default)]
-    args: Vec<String>,
-    #[serde(default)]
-    env: Vec<(String, String)>,
-    systems: Vec<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct System {
-    name: String,
-    rom_directory: PathBuf,
-    file_extensions: HashSet<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-struct MenuLayout {
-    #[serde(rename = "item")]
-    items: Vec<MenuEntry>,
-    #[serde(rename = "emulator")]
-    emulators: Vec<Emulator>,
-    #[serde(rename = "system")]
-    systems: Vec<System>,
-}
-
-impl MenuLayout {
-    fn mk_sgui_layout(self) -> (HashMap<u128, MenuEntry>, Layout) {
-        let mut id = 0;
-        let mut entry_map = HashMap::new();
-        let mut tools = Vec::new();
-        let mut programs = Vec::new();
-        let mut roms = Vec::new();
-
-        for item in self.items.into_iter() {
-            match item.category {
-                Category::Tools => tools.push((item, id)),
-                Category::Programs => programs.push((item, id)),
-            }
-            id += 1;
-        }
-
-        for system in self.systems.iter() {
-            if system.rom_directory.exists() {
-                let files = match fs::read_dir(&system.rom_directory) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        log_rust_error(&e, format!("Failed to open rom directory for {}", &system.name), LogPriority::Error);
-                        continue;
-                    }
-                };
-                let Some(emulator) = self.emulators.iter().find(|e| e.systems.contains(&system.name)) else {
-                    log_error(format!("Failed to find suitable emulator for system {}", &system.name));
-                    continue;
-                };
-
-                let mut system_tab = Vec::new();
-                for file in files {
-                    let Ok(file) = file else { continue };
-                    let Ok(filename) = file.file_name().into_string() else { continue };
-                    let ext = filename.split('.').last().unwrap_or("<invalid>");
-                    if !system.file_extensions.contains(&ext.to_string()) {
-                        log_error(format!("Wrong file extension for file {}. Expected one of: {:?}, Got: {}", filename, &system.file_extensions, ext));
-                        continue;
-                    }
-                    let fancy_name = filename.split('.').next().unwrap().to_string();
-                    let mut args = emulator.args.clone();
-                    args.push(file.path().into_os_string().into_string().unwrap_or("".to_string()));
-
-                    let entry = MenuEntry {
-                        name: fancy_name,
-                        category: Category::Tools, // ignored
-                        uses_wayland: true,
-                        executable: emulator.executable.clone(),
-                        args,
-                        env: emulator.env.clone(),
-                    };
-                    system_tab.push((entry, id));
-                    id += 1;
-                }
-                roms.push((system.name.clone(), system_tab));
-            } else {
-                log_error(format!("{}'s rom directory, {}, does not exist, skipping ", &system.name, system.rom_directory.display()));
-            }
-        }
-
-        let layout = Layout::builder();
-        let mut tools_tab = layout.tab("System Tools");
-        for (entry, id) in tools {
-            tools_tab = tools_tab.line().button_stateless(&entry.name, id).endl();
-            entry_map.insert(id, entry);
-        }
-        let layout = tools_tab.end_tab();
-
-        let mut programs_tab = layout.tab("Programs");
-        for (entry, id) in programs {
-            programs_tab = programs_tab.line().button_stateless(&entry.name, id).endl();
-            entry_map.insert(id, entry);
-        }
-        let mut layout = programs_tab.end_tab();
-
-        for (name, romtab) in roms {
-            let mut tab = layout.tab(&name);
-            for (entry, id) in romtab {
-                tab = tab.line().button_stateless(&entry.name, id).endl();
-                entry_map.insert(id, entry);
-            }
-            layout = tab.end_tab();
-        }
-        
-        (entry_map, layout.build())
-    }
-}
-
 ioctl_write_int_bad!(vt_activate, 0x5606);
 ioctl_write_int_bad!(vt_waitactive, 0x5607);
 fn switch_tty(num: i32, clear: bool) -> Result<()> {
@@ -220,6 +110,15 @@ fn run_with_dogd(program: &mut Command) -> Result<Child> {
 
 fn run_entry(e: &MenuEntry) -> Result<()> {
     log_debug(format!("Running {}", &e.name));
+    // Kept until the entry exits, dropping it removes the unpacked files
+    let (_extracted, file) = match (&e.file, &e.extract) {
+        (Some(archive), Some(inner)) => {
+            let extracted = Extracted::new(archive)?;
+            let file = extracted.path().join(inner);
+            (Some(extracted), Some(file))
+        },
+        _ => (None, e.file.clone()),
+    };
     let mut extra_env = e.env.clone();
     let mut weston_child;
     let stdin;
@@ -279,6 +178,7 @@ fn run_entry(e: &MenuEntry) -> Result<()> {
     envs.append(&mut extra_env);
     let mut tmp_cmd = Command::new(&e.executable);
     let mut cmd = tmp_cmd.args(&e.args)
+        .args(file.iter())
         .envs(envs)
         .stdin(stdin)
         .stdout(stdout)
@@ -308,69 +208,542 @@ fn run_entry(e: &MenuEntry) -> Result<()> {
     Ok(())
 }
 
-fn save_config(l: &MenuLayout, p: &Path) -> Result<()> {
-    let conf = toml::to_string(l).context("Failed to serialize config")?;
-    let mut conf_file = File::create(p).context("Failed to create/open config file")?;
-    conf_file.write_all(conf.as_bytes()).context("Failed to save config")?;
+enum Subcommand {
+    Gui,
+    Validate,
+    List,
+    Launch(String),
+    Migrate,
+    DumpConfig,
+}
+
+struct Args {
+    config_path: PathBuf,
+    /// Whether `config_path` came from the command line, it has to exist then
+    config_given: bool,
+    state_path: PathBuf,
+    /// `None` means looking in the usual per-user locations
+    user_config_path: Option<PathBuf>,
+    subcommand: Subcommand,
+}
+
+fn print_usage() {
+    println!("Usage: smenu [--config <path>] [--user-config <path>] [--state <path>] [--dump-config | validate | list | launch <id> | migrate]");
+    println!();
+    println!("Without a subcommand the menu GUI is started.");
+    println!("Fragments in the config's drop-in directory (/etc/smenu.d/*.toml for the");
+    println!("default config) are merged on top of it in file name order, followed by the");
+    println!("per-user config from $XDG_CONFIG_HOME/smenu/config.toml, ~/.config/smenu/config.toml");
+    println!("or /data/smenu/config.toml, whichever exists first.");
+    println!();
+    println!("Options:");
+    println!("  -c, --config <path>   Load the config from <path> instead of {}", DEFAULT_CONFIG_PATH);
+    println!("  -u, --user-config <path>");
+    println!("                        Load the per-user config from <path>");
+    println!("  -s, --state <path>    Keep runtime state in <path> instead of {}", default_state_path().display());
+    println!("      --dump-config     Print the effective config, with everything merged in, and exit");
+    println!("  -h, --help            Print this help");
+    println!();
+    println!("Subcommands:");
+    println!("  validate              Check the config and print every problem found in it");
+    println!("  list                  Print the ID and command line of every menu entry");
+    println!("  launch <id>           Run a single menu entry, as printed by list, without starting the GUI");
+    println!("  migrate               Upgrade every config file to the current version, keeping backups");
+    println!();
+    println!("Entry IDs are <category>/<item name> for items and <system>/<path> for ROMs, with");
+    println!("the path relative to the system's ROM directory, extension included. Category and");
+    println!("system names are the ones in the config, not the tab titles.");
+}
+
+fn parse_args() -> Result<Args> {
+    let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+    let mut config_given = false;
+    let mut user_config_path = None;
+    let mut state_path = default_state_path();
+    let mut subcommand = None;
+    let mut args = env::args_os().skip(1);
+
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.to_str() else {
+            return Err(anyhow!("Invalid argument {}", arg.to_string_lossy()));
+        };
+
+        match arg {
+            "-h" | "--help" => {
+                print_usage();
+                process::exit(0);
+            },
+            "-c" | "--config" => {
+                let path = args.next().ok_or_else(|| anyhow!("{} requires a path", arg))?;
+                config_path = PathBuf::from(path);
+                config_given = true;
+            },
+            _ if arg.starts_with("--config=") => {
+                config_path = PathBuf::from(&arg["--config=".len()..]);
+                config_given = true;
+            },
+            "-u" | "--user-config" => {
+                let path = args.next().ok_or_else(|| anyhow!("{} requires a path", arg))?;
+                user_config_path = Some(PathBuf::from(path));
+            },
+            _ if arg.starts_with("--user-config=") => {
+                user_config_path = Some(PathBuf::from(&arg["--user-config=".len()..]));
+            },
+            "-s" | "--state" => {
+                let path = args.next().ok_or_else(|| anyhow!("{} requires a path", arg))?;
+                state_path = PathBuf::from(path);
+            },
+            _ if arg.starts_with("--state=") => {
+                state_path = PathBuf::from(&arg["--state=".len()..]);
+            },
+            _ if subcommand.is_some() => return Err(anyhow!("Unexpected argument {}", arg)),
+            "--dump-config" => subcommand = Some(Subcommand::DumpConfig),
+            "validate" => subcommand = Some(Subcommand::Validate),
+            "list" => subcommand = Some(Subcommand::List),
+            "migrate" => subcommand = Some(Subcommand::Migrate),
+            "launch" => {
+                let target = args.next()
+                    .ok_or_else(|| anyhow!("launch requires an entry ID, as printed by list"))?
+                    .into_string()
+                    .map_err(|_| anyhow!("Entry to launch is not valid UTF-8"))?;
+                subcommand = Some(Subcommand::Launch(target));
+            },
+            _ => return Err(anyhow!("Unknown argument {}", arg)),
+        }
+    }
+
+    Ok(Args {
+        config_path,
+        config_given,
+        state_path,
+        user_config_path,
+        subcommand: subcommand.unwrap_or(Subcommand::Gui),
+    })
+}
+
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c)) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+fn command_line(e: &MenuEntry) -> String {
+    let mut parts = Vec::new();
+    for (key, value) in e.env.iter() {
+        parts.push(format!("{}={}", key, shell_quote(value)));
+    }
+    parts.push(shell_quote(&e.executable.to_string_lossy()));
+    for arg in e.args.iter() {
+        parts.push(shell_quote(arg));
+    }
+    if let Some(file) = &e.file {
+        parts.push(shell_quote(&file.to_string_lossy()));
+    }
+    parts.join(" ")
+}
+
+impl Args {
+    fn user_config_path(&self) -> Option<PathBuf> {
+        self.user_config_path.clone().or_else(user_overlay_path)
+    }
+
+    /// Without /etc/smenu.toml the built-in menu is used, but a config passed with --config
+    /// not being there is most likely a typo
+    fn check_config_path(&self) -> Result<()> {
+        if self.config_given && !self.config_path.exists() {
+            return Err(anyhow!("Config file {} doesn't exist", self.config_path.display()));
+        }
+        Ok(())
+    }
+}
+
+fn load_menu_layout(args: &Args) -> MenuLayout {
+    log_debug(format!("Loading config from {}", args.config_path.display()));
+    // Only fails when the built-in config is broken, leaving nothing but the problem to show
+    let layout = load_config(&args.config_path).unwrap_or_else(|e| {
+        log_rust_error(&*e, "Failed to load config", LogPriority::Error);
+        let mut layout = MenuLayout::default();
+        layout.problems.push(Problem::new(format!("{:#}", e)));
+        layout
+    });
+    check_menu_layout(apply_user_config(args, layout))
+}
+
+/// Applies the user config on top of an already loaded system config
+fn apply_user_config(args: &Args, mut layout: MenuLayout) -> MenuLayout {
+    if let Some(p) = args.user_config_path() {
+        log_debug(format!("Loading user config from {}", p.display()));
+        match load_overlay(&p) {
+            Ok(overlay) => overlay.apply(&mut layout),
+            Err(e) => {
+                log_rust_error(&*e, "Failed to load user config", LogPriority::Error);
+                layout.problems.push(Problem::new(format!("{:#}", e)));
+            },
+        }
+    }
+    layout
+}
+
+/// Expands variables and looks for things that parse fine but won't work, logging every problem
+fn check_menu_layout(mut layout: MenuLayout) -> MenuLayout {
+    layout.expand_variables(&Variables::new());
+    layout.check();
+    for problem in layout.problems.iter() {
+        log_error(problem.to_string());
+    }
+    layout
+}
+
+fn validate(args: &Args) -> Result<()> {
+    args.check_config_path()?;
+    let mut layout = load_config(&args.config_path)?;
+    if let Some(p) = args.user_config_path() {
+        load_overlay(&p)?.apply(&mut layout);
+    }
+    layout.expand_variables(&Variables::new());
+    layout.check();
+
+    if layout.problems.is_empty() {
+        println!("{}: OK", args.config_path.display());
+        return Ok(());
+    }
+    for problem in layout.problems.iter() {
+        println!("{}", problem);
+    }
+    Err(anyhow!("Found {} problem(s) in the config", layout.problems.len()))
+}
+
+fn list(args: &Args) -> Result<()> {
+    args.check_config_path()?;
+    let mut layout = load_menu_layout(args);
+    for problem in layout.problems.drain(..) {
+        eprintln!("warning: {}", problem);
+    }
+
+    let roms = layout.rom_tabs(Hashing::Now);
+    for (id, entry) in layout.into_menu(roms, &State::load(&args.state_path)).entries() {
+        println!("{}: {}", id, command_line(&entry));
+    }
     Ok(())
 }
 
-fn load_config(p: &Path) -> Result<MenuLayout> {
-    if !p.exists() {
-        return Err(anyhow!("Missing config file, using default"));
+fn launch(args: &Args, target: &str) -> Result<()> {
+    args.check_config_path()?;
+    let mut state = State::load(&args.state_path);
+    let layout = load_menu_layout(args);
+    let roms = layout.rom_tabs(Hashing::Now);
+    let menu = layout.into_menu(roms, &state);
+    let (tab, entry) = menu.find(target)
+        .and_then(|(t, path)| menu.entry(t, &path))
+        .ok_or_else(|| anyhow!("No menu entry with ID {}, see list for the valid ones", target))?;
+    run_entry(&entry)?;
+    state.record_launch(tab, target);
+    state.save(&args.state_path)
+}
+
+fn watch_config(watcher: &mut Watcher, args: &Args, layout: &MenuLayout, roms: &[RomTab]) {
+    watcher.clear();
+    watcher.watch_file(&args.config_path);
+    watcher.watch_dir(&drop_in_dir(&args.config_path));
+    // The user config can be created while running, so every place it could be at is watched
+    match &args.user_config_path {
+        Some(p) => watcher.watch_file(p),
+        None => for p in user_overlay_paths() {
+            watcher.watch_file(&p);
+        },
+    }
+    // Subdirectories of ROM directories too, as deep as they were scanned
+    for system in layout.systems.iter() {
+        match roms.iter().find(|r| r.name == system.name) {
+            Some(rom_tab) => for dir in rom_tab.dirs.iter() {
+                watcher.watch_listing(dir);
+            },
+            None => watcher.watch_listing(&system.rom_directory),
+        }
     }
-    let conf = fs::read_to_string(p).context("Failed to read config file")?;
-    let config = toml::from_str(&conf).context("Failed to deserialize config file")?;
-    Ok(config)
 }
 
-fn load_default_config() -> Result<MenuLayout> {
-    let conf = include_str!("default.toml");
-    Ok(toml::from_str(conf)?)
+fn dump_config(args: &Args) -> Result<String> {
+    args.check_config_path()?;
+    let layout = load_menu_layout(args);
+    layout.dump(&layout.rom_tabs(Hashing::Now))
 }
 
-fn main() {
-    let config_path = PathBuf::from("/etc/smenu.toml");
-    log_debug(format!("Loading config from {}", config_path.display()));
-    //let menu_layout = load_config(&config_path).unwrap_or(load_default_config().unwrap());
-    let menu_layout = match load_config(&config_path) {
-        Ok(c) => c,
+fn migrate_configs(args: &Args) -> Result<()> {
+    let mut files = vec![args.config_path.clone()];
+    files.extend(drop_in_fragments(&drop_in_dir(&args.config_path))?);
+    files.extend(args.user_config_path());
+
+    for p in files {
+        if migrate_file(&p)? {
+            println!("{}: upgraded to version {}", p.display(), CURRENT_VERSION);
+        } else {
+            println!("{}: already at version {}", p.display(), CURRENT_VERSION);
+        }
+    }
+    Ok(())
+}
+
+/// Runs an entry while keeping the GUI from reacting to input, returning whether it ran
+fn run_entry_from_gui(gui: &mut Gui, entry: &MenuEntry) -> bool {
+    gui.set_ignore_hid(true);
+    let launched = thread::scope(|s| {
+        let h = s.spawn(move || match run_entry(entry) {
+            Ok(()) => true,
+            Err(e) => {
+                log_rust_error(&*e, "Failed to run menu entry", LogPriority::Error);
+                false
+            },
+        });
+        while !h.is_finished() {
+            let _ = gui.get_ev();
+        }
+        h.join().unwrap_or(false)
+    });
+    gui.set_ignore_hid(false);
+    launched
+}
+
+/// Runs the entry at `path` in `tab`, remembering it was played and the tab it was launched
+/// from, and sorts its tab again. Returns whether it ran, the menu needs rebuilding then for
+/// the recent tab and sorting by last played.
+fn launch_from_gui(gui: &mut Gui, menu: &mut Menu, state: &mut State, args: &Args, tab: usize, path: &[usize]) -> bool {
+    let (Some((tab_name, entry)), Some(id)) = (menu.entry(tab, path), menu.id(tab, path)) else { return false };
+    if !run_entry_from_gui(gui, &entry) {
+        return false;
+    }
+    // The launch button's tab has been shown before getting here
+    state.record_launch(menu.current_tab_name().unwrap_or(tab_name), &id);
+    menu.resort(tab, state);
+    // Saved right away, handhelds tend to get switched off rather than quit
+    if let Err(e) = state.save(&args.state_path) {
+        log_rust_error(&*e, "Failed to save state", LogPriority::Error);
+    }
+    true
+}
+
+/// Hashes the ROMs `rom_tabs` left unverified on a thread of its own, the first scan of a big
+/// set can take minutes. The menu gets reloaded with what the DAT files say once it's done.
+fn hash_in_background(roms: &[RomTab], hashing: &mut Option<JoinHandle<()>>) {
+    if roms.iter().all(|r| r.unhashed == 0) || hashing.as_ref().is_some_and(|h| !h.is_finished()) {
+        return;
+    }
+    log_info(format!("Hashing {} ROMs in the background", roms.iter().map(|r| r.unhashed).sum::<usize>()));
+    let verified: Vec<_> = roms.iter().flat_map(|r| r.verified.iter().cloned()).collect();
+    *hashing = Some(thread::spawn(move || {
+        if hash_all(&verified) {
+            request_reload();
+        }
+    }));
+}
+
+/// Loads the config again, returning the menu made from it if it's usable and anything changed.
+/// `dump` and `problems` are what the current menu was built from.
+fn reload(args: &Args, watcher: &mut Watcher, hashing: &mut Option<JoinHandle<()>>, state: &State, dump: &mut String, problems: &mut Vec<String>) -> Option<Menu> {
+    log_info("Reloading config");
+    let layout = match load_config(&args.config_path).map(|layout| apply_user_config(args, layout)) {
+        Ok(layout) => layout,
+        Err(e) => {
+            log_rust_error(&*e, "Failed to reload config, keeping the old menu", LogPriority::Critical);
+            return None;
+        },
+    };
+
+    // Only problems in the files themselves mean they're broken or still being written.
+    // Missing emulators or ROM directories get shown like at startup.
+    let broken: Vec<String> = layout.problems.iter()
+        .map(Problem::to_string)
+        .filter(|p| !problems.contains(p))
+        .collect();
+    if !broken.is_empty() {
+        log_critical(format!("The new config has problems, keeping the old menu:\n{}", broken.join("\n")));
+        return None;
+    }
+
+    let menu_layout = check_menu_layout(layout);
+    let roms = menu_layout.rom_tabs(Hashing::Cached);
+    watch_config(watcher, args, &menu_layout, &roms);
+    hash_in_background(&roms, hashing);
+    let new_problems: Vec<String> = menu_layout.problems.iter().map(Problem::to_string).collect();
+    let new_dump = menu_layout.dump(&roms).unwrap_or_default();
+    if new_dump == *dump && new_problems == *problems {
+        log_debug("Nothing changed, keeping the menu as it is");
+        return None;
+    }
+    *dump = new_dump;
+    *problems = new_problems;
+    Some(menu_layout.into_menu(roms, state))
+}
+
+fn run_gui(args: &Args) {
+    let menu_layout = load_menu_layout(args);
+
+    // Scanned once for both the dump and the menu
+    let roms = menu_layout.rom_tabs(Hashing::Cached);
+
+    let mut watcher = Watcher::new();
+    watch_config(&mut watcher, args, &menu_layout, &roms);
+    let mut hashing = None;
+    hash_in_background(&roms, &mut hashing);
+    if let Err(e) = reload_on_sighup() {
+        log_rust_error(&*e, "Failed to set up reloading on SIGHUP", LogPriority::Error);
+    }
+
+    // What the menu was built from, so a reload only replaces it when something changed
+    let mut dump = match menu_layout.dump(&roms) {
+        Ok(dump) => {
+            log_debug(format!("Effective config:\n{}", dump));
+            dump
+        },
         Err(e) => {
-            log_rust_error(&*e, "Failed to load config", LogPriority::Error);
-            load_default_config().unwrap()
+            log_rust_error(&*e, "Failed to dump effective config", LogPriority::Error);
+            String::new()
         },
     };
+    let mut problems: Vec<String> = menu_layout.problems.iter().map(Problem::to_string).collect();
+
+    let mut state = State::load(&args.state_path);
 
-    let (entries, layout) = menu_layout.mk_sgui_layout();
+    let mut menu = menu_layout.into_menu(roms, &state);
+    let (mut actions, layout) = menu.build(&state);
     log_debug("Smenu starting up");
     let mut gui = Gui::new(layout);
-    let state = loop {
+    let mut rebuild = false;
+    // Launched once the confirmation dialog is gone
+    let mut confirmed: Option<(usize, NodePath)> = None;
+    loop {
+        if rebuild {
+            let (new_actions, layout) = menu.build(&state);
+            gui.exit_dumping_state();
+            gui = Gui::new(layout);
+            actions = new_actions;
+            rebuild = false;
+        }
+
+        if let Some((tab, path)) = confirmed.take() {
+            if launch_from_gui(&mut gui, &mut menu, &mut state, args, tab, &path) {
+                rebuild = true;
+                continue;
+            }
+        }
+
         let ev = gui.get_ev();
+        // sgui can't be woken up, so reloads only get noticed after the next input. It was
+        // meant for the old menu, so it's dropped when the menu gets replaced.
+        if !matches!(ev, GuiEvent::Quit) && watcher.reload_requested() {
+            if let Some(new_menu) = reload(args, &mut watcher, &mut hashing, &state, &mut dump, &mut problems) {
+                menu = new_menu;
+                rebuild = true;
+                continue;
+            }
+        }
         match ev {
             GuiEvent::Quit => {
-                let state = gui.exit_dumping_state();
-                break state;
+                gui.exit_dumping_state();
+                break;
             },
-            GuiEvent::StatelessButtonPress(_, id) => {
-                if let Some(entry) = entries.get(&id) {
-                    gui.set_ignore_hid(true);
-                    thread::scope(|s| {
-                        let h = s.spawn(move || {if let Err(e) = run_entry(&entry) {
-                            log_rust_error(&*e, "Failed to run menu entry", LogPriority::Error);
-                        }});
-                        while !h.is_finished() {
-                            let _ = gui.get_ev();
+            GuiEvent::StatelessButtonPress(_, id) => match actions.get(&id).cloned() {
+                Some(Action::Launch(shown, tab, path)) => {
+                    if menu.entry(tab, &path).is_some_and(|(_, e)| e.confirm) {
+                        menu.ask(shown, tab, path);
+                        rebuild = true;
+                    } else {
+                        menu.show(shown);
+                        rebuild = launch_from_gui(&mut gui, &mut menu, &mut state, args, tab, &path);
+                    }
+                },
+                Some(Action::Confirm(yes)) => {
+                    let answered = menu.answer();
+                    if yes {
+                        confirmed = answered;
+                    }
+                    rebuild = true;
+                },
+                Some(Action::Open(tab, path)) => {
+                    menu.open(tab, path);
+                    rebuild = true;
+                },
+                Some(Action::Back(tab)) => {
+                    menu.back(tab);
+                    rebuild = true;
+                },
+                Some(Action::Page(tab, page)) => {
+                    menu.page(tab, page);
+                    rebuild = true;
+                },
+                Some(Action::Type(c)) => {
+                    menu.edit_search(|s| s.push(c));
+                    rebuild = true;
+                },
+                Some(Action::Erase) => {
+                    menu.edit_search(|s| { s.pop(); });
+                    rebuild = true;
+                },
+                Some(Action::ClearSearch) => {
+                    menu.edit_search(String::clear);
+                    rebuild = true;
+                },
+                Some(Action::MarkFavorites(tab)) => {
+                    menu.toggle_marking(tab);
+                    rebuild = true;
+                },
+                Some(Action::ToggleFavorite(shown, tab, path)) => {
+                    if let Some(id) = menu.id(tab, &path) {
+                        state.toggle_favorite(id);
+                        if let Err(e) = state.save(&args.state_path) {
+                            log_rust_error(&*e, "Failed to save state", LogPriority::Error);
+                        }
+                    }
+                    menu.show(shown);
+                    rebuild = true;
+                },
+                Some(Action::CycleSort(tab)) => {
+                    if let Some((name, mode)) = menu.cycle_sort(tab, &state) {
+                        state.sort_modes.insert(name.to_string(), mode);
+                        if let Err(e) = state.save(&args.state_path) {
+                            log_rust_error(&*e, "Failed to save state", LogPriority::Error);
                         }
-                    });
-                    gui.set_ignore_hid(false);
-                }
+                    }
+                    rebuild = true;
+                },
+                None => (),
             },
             _ => (),
         }
+    }
+
+    log_debug(format!("Saving state into {}", args.state_path.display()));
+    if let Err(e) = state.save(&args.state_path) {
+        log_rust_error(&*e, "Failed to save state", LogPriority::Critical);
+    }
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("smenu: {}", e);
+            print_usage();
+            process::exit(2);
+        },
     };
 
-    log_debug(format!("Saving config into {}", config_path.display()));
-    //if let Err(e) = save_config(&menu_layout, &config_path) {
-    //    log_rust_error(&*e, "Failed to save menu config", LogPriority::Critical);
-    //}
+    let result = match &args.subcommand {
+        Subcommand::Gui => {
+            run_gui(&args);
+            Ok(())
+        },
+        Subcommand::Validate => validate(&args),
+        Subcommand::List => list(&args),
+        Subcommand::Launch(target) => launch(&args, target),
+        Subcommand::Migrate => migrate_configs(&args),
+        Subcommand::DumpConfig => dump_config(&args).map(|dump| print!("{}", dump)),
+    };
+
+    if let Err(e) = result {
+        eprintln!("smenu: {:#}", e);
+        process::exit(1);
+    }
 }
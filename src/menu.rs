@@ -0,0 +1,998 @@
+use sgui::layout::Layout;
+
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+use libdogd::{log_error, LogPriority, log_rust_error};
+
+use crate::archive::{is_archive, ArchiveFile, ArchiveMode};
+use crate::cache::FileCache;
+use crate::config::{Emulator, MenuEntry, MenuLayout, Subfolders, System};
+use crate::dat::{Dat, Dump, HashCache, Hashing};
+use crate::discs::{group_discs, hide_tracks};
+use crate::roms::{find_in_archive, scan_dir, split_name};
+use crate::vars::data_dir;
+use crate::search::{self, Match, PICKER_ROWS};
+use crate::sort::{NaturalKey, SortMode, strip_article};
+use crate::state::State;
+
+/// Buttons shown per page of a tab, building thousands of them at once is too slow
+const PAGE_SIZE: usize = 50;
+
+/// Name of the search tab, for [tabs]
+pub const SEARCH_TAB: &str = "Search";
+/// Name of the favorites tab, for [tabs]
+pub const FAVORITES_TAB: &str = "Favorites";
+/// Name of the recently played tab, for [tabs]
+pub const RECENT_TAB: &str = "Recent";
+
+/// A file found in a system's ROM directory. Kept small since there can be thousands of them,
+/// the command line only gets built when it's launched.
+#[derive(Debug)]
+pub struct Rom {
+    pub name: String,
+    pub path: PathBuf,
+    /// Path below the system's ROM directory, which its ID is made of. For a playlist it's
+    /// the first disc's.
+    pub key: PathBuf,
+    /// Directory below the system's ROM directory it's shown in, empty when flattened
+    pub folder: PathBuf,
+    /// Path of the ROM inside `path` when that's an archive
+    pub inner: Option<PathBuf>,
+    /// Index into the layout's systems
+    pub system: usize,
+    /// Index into the layout's emulators
+    pub emulator: usize,
+    /// When the file showed up, going by its modification time
+    pub added: SystemTime,
+    /// What the system's DAT file says about it, if it has one
+    pub dump: Option<Dump>,
+}
+
+/// Everything found for one system
+#[derive(Debug)]
+pub struct RomTab {
+    pub name: String,
+    pub sorting: Sorting,
+    pub roms: Vec<Rom>,
+    /// Every directory that was scanned, for watching
+    pub dirs: Vec<PathBuf>,
+    /// Every ROM checked against the system's DAT file, with the file in it for archives
+    pub verified: Vec<(PathBuf, Option<ArchiveFile>)>,
+    /// How many of them were left unverified for not having been hashed yet
+    pub unhashed: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sorting {
+    pub mode: SortMode,
+    pub ignore_articles: bool,
+}
+
+/// Something listed in a tab
+#[derive(Debug)]
+pub enum Node {
+    Entry(MenuEntry),
+    Rom(Rom),
+    Folder(Folder),
+    /// Text that does nothing when pressed
+    Note(String),
+}
+
+#[derive(Debug)]
+pub struct Folder {
+    pub name: String,
+    pub children: Vec<Node>,
+}
+
+/// What a tab shows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TabKind {
+    /// Its own nodes
+    Nodes,
+    /// The character picker, results get a tab of their own
+    Search,
+    /// Entries marked as favorites, looked up by their IDs
+    Favorites,
+    /// Entries launched last, most recent first
+    Recent,
+}
+
+#[derive(Debug)]
+pub struct Tab {
+    /// Category or system name, what [tabs] and entry IDs refer to
+    pub name: String,
+    pub title: String,
+    pub nodes: Vec<Node>,
+    /// `None` keeps the nodes in the order they were defined in
+    pub sorting: Option<Sorting>,
+    pub kind: TabKind,
+}
+
+/// Position of a node in a tab, as indices into each level of folders from the tab's root
+pub type NodePath = Vec<usize>;
+
+/// What pressing a button does
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Runs an entry, as (tab the button is in, tab of the entry, path)
+    Launch(usize, usize, NodePath),
+    Open(usize, NodePath),
+    Back(usize),
+    CycleSort(usize),
+    /// Shows another page of the tab's open folder
+    Page(usize, usize),
+    /// Adds a character to the search
+    Type(char),
+    /// Removes the last character of the search
+    Erase,
+    ClearSearch,
+    /// Switches between launching entries and marking them as favorites, staying on the tab
+    MarkFavorites(usize),
+    /// Marks or unmarks an entry as a favorite, as (tab the button is in, tab of the entry, path)
+    ToggleFavorite(usize, usize, NodePath),
+    /// Answers the confirmation dialog
+    Confirm(bool),
+}
+
+/// The whole menu as a tree, along with which folder is open in every tab
+#[derive(Debug)]
+pub struct Menu {
+    tabs: Vec<Tab>,
+    open: Vec<NodePath>,
+    /// Page of the open folder shown in every tab
+    pages: Vec<usize>,
+    /// Tab shown first when building the layout
+    current_tab: usize,
+    search: String,
+    /// Pressing entries marks them as favorites instead of launching them
+    marking: bool,
+    /// How many entries the recent tab lists
+    recent_count: usize,
+    /// Whether the recent tab starts with a button continuing the last game played
+    continue_last: bool,
+    /// Entry waiting for a yes or no before it's run, shown instead of the tabs, as (tab the
+    /// button was in, tab of the entry, path)
+    confirming: Option<(usize, usize, NodePath)>,
+    /// Where every entry is, keyed by ID
+    ids: HashMap<String, (usize, NodePath)>,
+    /// What ROMs refer to
+    systems: Vec<System>,
+    emulators: Vec<Emulator>,
+}
+
+impl Rom {
+    /// The entry launching this ROM with its emulator
+    pub fn entry(&self, system: &System, emulator: &Emulator) -> MenuEntry {
+        MenuEntry {
+            name: self.name.clone(),
+            category: system.name.clone(),
+            parent: None,
+            uses_wayland: true,
+            executable: emulator.executable.clone(),
+            args: emulator.args.clone(),
+            env: emulator.env.clone(),
+            confirm: false,
+            confirm_message: None,
+            file: Some(self.path.clone()),
+            extract: match emulator.archives {
+                ArchiveMode::Extract => self.inner.clone(),
+                ArchiveMode::Pass => None,
+            },
+            builtin: false,
+        }
+    }
+}
+
+impl Node {
+    fn name(&self) -> &str {
+        match self {
+            Node::Entry(e) => &e.name,
+            Node::Rom(r) => &r.name,
+            Node::Folder(f) => &f.name,
+            Node::Note(text) => text,
+        }
+    }
+
+    /// What its button says, flagging ROMs the system's DAT file doesn't vouch for
+    fn label(&self) -> Cow<'_, str> {
+        match self {
+            Node::Rom(Rom { name, dump: Some(Dump::Bad), .. }) => format!("{} [bad dump]", name).into(),
+            Node::Rom(Rom { name, dump: Some(Dump::Unknown), .. }) => format!("{} [unknown dump]", name).into(),
+            _ => self.name().into(),
+        }
+    }
+}
+
+/// ID of an entry in tab `tab` as `<tab>/<name>`, or `<tab>/<path below the ROM directory>`
+/// for ROMs since subfolders can have ROMs with the same name. It's the tab's name rather than
+/// its title. Unlike button IDs or paths it stays the same when ROMs are added or the config changes.
+fn node_id(tab: &str, node: &Node) -> Option<String> {
+    match node {
+        Node::Entry(e) => Some(format!("{}/{}", tab, e.name)),
+        Node::Rom(r) => Some(format!("{}/{}", tab, r.key.to_string_lossy())),
+        _ => None,
+    }
+}
+
+/// Orders nodes by `sorting`, folders first, using play statistics from `state`. Keys are
+/// worked out once per node rather than on every comparison, which matters for big tabs.
+fn sort_nodes(nodes: &mut [Node], tab: &str, sorting: Sorting, state: &State) {
+    for node in nodes.iter_mut() {
+        if let Node::Folder(f) = node {
+            sort_nodes(&mut f.children, tab, sorting, state);
+        }
+    }
+
+    nodes.sort_by_cached_key(|n| {
+        let stats = |stats: &BTreeMap<String, u64>| node_id(tab, n).and_then(|id| stats.get(&id).copied()).unwrap_or(0);
+        // Most recent and most played first
+        let stat = match (sorting.mode, n) {
+            (_, Node::Folder(_)) | (SortMode::Name, _) => 0,
+            (SortMode::LastPlayed, _) => stats(&state.last_played),
+            (SortMode::MostPlayed, _) => stats(&state.play_counts),
+            (SortMode::DateAdded, Node::Rom(r)) => r.added.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            (SortMode::DateAdded, _) => 0,
+        };
+        let name = match sorting.ignore_articles {
+            true => strip_article(n.name()),
+            false => n.name(),
+        };
+        (!matches!(n, Node::Folder(_)), Reverse(stat), NaturalKey(name.to_string()))
+    });
+}
+
+impl Tab {
+    fn new(name: String, title: String, kind: TabKind) -> Tab {
+        Tab {
+            name,
+            title,
+            nodes: Vec::new(),
+            sorting: None,
+            kind,
+        }
+    }
+
+    fn sort(&mut self, state: &State) {
+        if let Some(sorting) = self.sorting {
+            sort_nodes(&mut self.nodes, &self.name, sorting, state);
+        }
+    }
+
+    fn children(&self, path: &[usize]) -> &[Node] {
+        let mut nodes = &self.nodes[..];
+        for &i in path {
+            match nodes.get(i) {
+                Some(Node::Folder(f)) => nodes = &f.children,
+                _ => return &self.nodes,
+            }
+        }
+        nodes
+    }
+
+    fn node(&self, path: &[usize]) -> Option<&Node> {
+        let (last, parent) = path.split_last()?;
+        self.children(parent).get(*last)
+    }
+
+    /// Puts a node into the folder at `parent`, creating folders on the way
+    fn insert(nodes: &mut Vec<Node>, parent: &[&str], node: Node) {
+        let Some((first, rest)) = parent.split_first() else {
+            nodes.push(node);
+            return;
+        };
+
+        let idx = match nodes.iter().position(|n| matches!(n, Node::Folder(f) if f.name == *first)) {
+            Some(i) => i,
+            None => {
+                nodes.push(Node::Folder(Folder {
+                    name: first.to_string(),
+                    children: Vec::new(),
+                }));
+                nodes.len() - 1
+            },
+        };
+        if let Node::Folder(folder) = &mut nodes[idx] {
+            Tab::insert(&mut folder.children, rest, node);
+        }
+    }
+}
+
+/// Adds the ID of every entry in `nodes` to `ids`, with where it is
+fn index_nodes(nodes: &[Node], tab: usize, name: &str, path: &mut NodePath, ids: &mut HashMap<String, (usize, NodePath)>) {
+    for (i, node) in nodes.iter().enumerate() {
+        path.push(i);
+        match node {
+            Node::Folder(f) => index_nodes(&f.children, tab, name, path, ids),
+            _ => if let Some(id) = node_id(name, node) {
+                ids.insert(id, (tab, path.clone()));
+            },
+        }
+        path.pop();
+    }
+}
+
+/// Finds every entry in `nodes` matching `query`, with the path to it
+fn collect_matches(nodes: &[Node], query: &str, path: &mut NodePath, ret: &mut Vec<(Match, NodePath)>) {
+    for (i, node) in nodes.iter().enumerate() {
+        path.push(i);
+        match node {
+            Node::Folder(f) => collect_matches(&f.children, query, path, ret),
+            Node::Entry(_) | Node::Rom(_) => ret.extend(search::find(node.name(), query).map(|m| (m, path.clone()))),
+            Node::Note(_) => (),
+        }
+        path.pop();
+    }
+}
+
+impl Menu {
+    fn node_entry<'a>(&'a self, node: &'a Node) -> Option<Cow<'a, MenuEntry>> {
+        match node {
+            Node::Entry(e) => Some(Cow::Borrowed(e)),
+            Node::Rom(r) => Some(Cow::Owned(r.entry(&self.systems[r.system], &self.emulators[r.emulator]))),
+            _ => None,
+        }
+    }
+
+    fn collect_entries<'a>(&'a self, tab: &str, nodes: &'a [Node], ret: &mut Vec<(String, Cow<'a, MenuEntry>)>) {
+        for node in nodes {
+            match node {
+                Node::Folder(f) => self.collect_entries(tab, &f.children, ret),
+                _ => ret.extend(node_id(tab, node).zip(self.node_entry(node))),
+            }
+        }
+    }
+
+    /// Every entry along with its ID, folders flattened
+    pub fn entries(&self) -> Vec<(String, Cow<'_, MenuEntry>)> {
+        let mut ret = Vec::new();
+        for tab in self.tabs.iter() {
+            self.collect_entries(&tab.name, &tab.nodes, &mut ret);
+        }
+        ret
+    }
+
+    /// The entry at `path` along with the name of the tab it's in
+    pub fn entry(&self, tab: usize, path: &[usize]) -> Option<(&str, Cow<'_, MenuEntry>)> {
+        let tab = self.tabs.get(tab)?;
+        Some((&tab.name, self.node_entry(tab.node(path)?)?))
+    }
+
+    pub fn id(&self, tab: usize, path: &[usize]) -> Option<String> {
+        let tab = self.tabs.get(tab)?;
+        node_id(&tab.name, tab.node(path)?)
+    }
+
+    /// Where the entry with `id` currently is
+    pub fn find(&self, id: &str) -> Option<(usize, NodePath)> {
+        self.ids.get(id).cloned()
+    }
+
+    /// Works out where every entry is for `find`, which favorites and the recent tab look up on
+    /// every build. Has to be done again whenever a tab gets sorted.
+    fn index(&mut self) {
+        self.ids.clear();
+        for (t, tab) in self.tabs.iter().enumerate() {
+            index_nodes(&tab.nodes, t, &tab.name, &mut Vec::new(), &mut self.ids);
+        }
+    }
+
+    /// Entries launched last that are still around, most recent first
+    fn recent(&self, state: &State) -> Vec<(usize, NodePath)> {
+        let mut played: Vec<(&String, &u64)> = state.last_played.iter().collect();
+        played.sort_by(|a, b| b.1.cmp(a.1));
+        played.into_iter()
+            .filter_map(|(id, _)| self.find(id))
+            .take(self.recent_count)
+            .collect()
+    }
+
+    fn tab_of_kind(&self, kind: TabKind) -> Option<usize> {
+        self.tabs.iter().position(|t| t.kind == kind)
+    }
+
+    /// Name of the tab shown first, `None` when [tabs] hides them all
+    pub fn current_tab_name(&self) -> Option<&str> {
+        self.tabs.get(self.current_tab).map(|t| t.name.as_str())
+    }
+
+    /// Goes back to where the menu was left last time: the folder and page of the last entry
+    /// launched, and the last tab shown unless the config picks a startup tab
+    fn restore(&mut self, state: &State, to_last_tab: bool) {
+        if let Some((t, mut path)) = state.last_entry.as_ref().and_then(|id| self.find(id)) {
+            if let Some(i) = path.pop() {
+                self.open[t] = path;
+                self.pages[t] = i / PAGE_SIZE;
+            }
+        }
+        if to_last_tab {
+            if let Some(t) = state.last_tab.as_ref().and_then(|name| self.tabs.iter().position(|t| t.name == *name)) {
+                self.show(t);
+            }
+        }
+    }
+
+    /// Makes `tab` the one shown after rebuilding
+    pub fn show(&mut self, tab: usize) {
+        if tab < self.tabs.len() {
+            self.current_tab = tab;
+        }
+    }
+
+    /// Asks whether to run the entry at `path` on the next build, going back to tab `shown`
+    /// once answered
+    pub fn ask(&mut self, shown: usize, tab: usize, path: NodePath) {
+        self.show(shown);
+        self.confirming = Some((shown, tab, path));
+    }
+
+    /// Closes the confirmation dialog, returning the entry it was about
+    pub fn answer(&mut self) -> Option<(usize, NodePath)> {
+        let (shown, tab, path) = self.confirming.take()?;
+        self.show(shown);
+        Some((tab, path))
+    }
+
+    pub fn toggle_marking(&mut self, tab: usize) {
+        self.marking = !self.marking;
+        self.show(tab);
+    }
+
+    /// Switches a tab to the next sort mode, returning the tab's name and the new mode so it
+    /// can be remembered
+    pub fn cycle_sort(&mut self, tab: usize, state: &State) -> Option<(&str, SortMode)> {
+        let t = self.tabs.get_mut(tab)?;
+        let sorting = t.sorting.as_mut()?;
+        sorting.mode = sorting.mode.next();
+        let mode = sorting.mode;
+        t.sort(state);
+        self.index();
+        self.pages[tab] = 0;
+        self.current_tab = tab;
+        Some((&self.tabs[tab].name, mode))
+    }
+
+    /// Sorts `tab` again after its play statistics changed. Folders stay where they are in
+    /// every sort mode, so the open folders still point at the same ones.
+    pub fn resort(&mut self, tab: usize, state: &State) {
+        if let Some(t) = self.tabs.get_mut(tab) {
+            t.sort(state);
+            self.index();
+        }
+    }
+
+    pub fn open(&mut self, tab: usize, path: NodePath) {
+        if let Some(open) = self.open.get_mut(tab) {
+            *open = path;
+            self.pages[tab] = 0;
+            self.current_tab = tab;
+        }
+    }
+
+    pub fn back(&mut self, tab: usize) {
+        if let Some(open) = self.open.get_mut(tab) {
+            open.pop();
+            self.pages[tab] = 0;
+            self.current_tab = tab;
+        }
+    }
+
+    pub fn page(&mut self, tab: usize, page: usize) {
+        if let Some(p) = self.pages.get_mut(tab) {
+            *p = page;
+            self.current_tab = tab;
+        }
+    }
+
+    /// Changes the search with `f`, staying on the search tab
+    pub fn edit_search(&mut self, f: impl FnOnce(&mut String)) {
+        f(&mut self.search);
+        if let Some(t) = self.tab_of_kind(TabKind::Search) {
+            self.current_tab = t;
+        }
+    }
+
+    /// Entries matching the search in every tab as (tab, path), best matches first
+    fn search_results(&self) -> Vec<(usize, NodePath)> {
+        let mut results = Vec::new();
+        for (t, tab) in self.tabs.iter().enumerate() {
+            let mut matches = Vec::new();
+            collect_matches(&tab.nodes, &self.search, &mut Vec::new(), &mut matches);
+            results.extend(matches.into_iter().map(|(m, path)| (m, t, path)));
+        }
+
+        results.sort_by_cached_key(|(m, t, path)| {
+            let name = self.tabs[*t].node(path).map_or("", Node::name);
+            (*m, NaturalKey(name.to_string()))
+        });
+        results.into_iter().map(|(_, t, path)| (t, path)).collect()
+    }
+
+    /// Label and action of an entry's button in tab `shown`, depending on whether favorites
+    /// are being marked
+    fn entry_button(&self, state: &State, name: &str, shown: usize, tab: usize, path: NodePath) -> (String, Action) {
+        if !self.marking {
+            return (name.to_string(), Action::Launch(shown, tab, path));
+        }
+        let favorite = self.id(tab, &path).is_some_and(|id| state.is_favorite(&id));
+        let star = if favorite { "*" } else { "-" };
+        (format!("{} {}", star, name), Action::ToggleFavorite(shown, tab, path))
+    }
+
+    /// Builds the GUI layout along with what every button does. sgui always opens on the first
+    /// tab, so tabs are rotated to start at the current one. Only one page of every tab's open
+    /// folder gets buttons.
+    pub fn build(&self, state: &State) -> (HashMap<u128, Action>, Layout) {
+        let mut actions = HashMap::new();
+
+        if let Some((_, t, path)) = &self.confirming {
+            let message = self.entry(*t, path).map_or_else(String::new, |(_, e)| e.confirm_message());
+            // The message gets the focus, so pressing twice by accident doesn't answer
+            let layout = Layout::builder()
+                .tab("Confirm")
+                .line().button_stateless(&message, 0).endl()
+                .line().button_stateless("No", 1).button_stateless("Yes", 2).endl()
+                .end_tab();
+            actions.insert(1, Action::Confirm(false));
+            actions.insert(2, Action::Confirm(true));
+            return (actions, layout.build());
+        }
+
+        let mut layout = Layout::builder();
+        let mut id = 0;
+
+        let count = self.tabs.len();
+        for t in (0..count).map(|i| (i + self.current_tab) % count) {
+            let tab = &self.tabs[t];
+            let path = &self.open[t];
+            let mut builder = layout.tab(&tab.title);
+
+            let has_entries = tab.nodes.iter().any(|n| !matches!(n, Node::Note(_)));
+            if matches!(tab.kind, TabKind::Favorites | TabKind::Recent) || (tab.kind == TabKind::Nodes && has_entries) {
+                let label = if self.marking { "Done marking favorites" } else { "Mark favorites" };
+                builder = builder.line().button_stateless(label, id).endl();
+                actions.insert(id, Action::MarkFavorites(t));
+                id += 1;
+            }
+
+            if matches!(tab.kind, TabKind::Favorites | TabKind::Recent) {
+                let (listed, empty) = match tab.kind {
+                    TabKind::Favorites => (state.favorites.iter().filter_map(|f| self.find(f)).collect(), "No favorites yet"),
+                    _ => (self.recent(state), "Nothing played yet"),
+                };
+
+                if tab.kind == TabKind::Recent && self.continue_last && !self.marking {
+                    let last_game = listed.iter().find(|(lt, lpath)| matches!(self.tabs[*lt].node(lpath), Some(Node::Rom(_))));
+                    if let Some((lt, lpath)) = last_game {
+                        let name = self.tabs[*lt].node(lpath).map_or("", Node::name);
+                        builder = builder.line().button_stateless(&format!("Continue {}", name), id).endl();
+                        actions.insert(id, Action::Launch(t, *lt, lpath.clone()));
+                        id += 1;
+                    }
+                }
+
+                for (lt, lpath) in listed.iter() {
+                    let name = self.tabs[*lt].node(lpath).map_or("".into(), Node::label);
+                    let name = format!("{} ({})", name, self.tabs[*lt].title);
+                    let (label, action) = self.entry_button(state, &name, t, *lt, lpath.clone());
+                    builder = builder.line().button_stateless(&label, id).endl();
+                    actions.insert(id, action);
+                    id += 1;
+                }
+                if listed.is_empty() {
+                    builder = builder.line().button_stateless(empty, id).endl();
+                    id += 1;
+                }
+                layout = builder.end_tab();
+                continue;
+            }
+
+            if tab.kind == TabKind::Search {
+                builder = builder.line().button_stateless(&format!("Search: {}_", self.search), id).endl();
+                id += 1;
+                for row in PICKER_ROWS {
+                    let mut line = builder.line();
+                    for c in row.chars() {
+                        line = line.button_stateless(&c.to_string(), id);
+                        actions.insert(id, Action::Type(c));
+                        id += 1;
+                    }
+                    builder = line.endl();
+                }
+                builder = builder.line()
+                    .button_stateless("Space", id)
+                    .button_stateless("Delete", id + 1)
+                    .button_stateless("Clear", id + 2)
+                    .endl();
+                actions.insert(id, Action::Type(' '));
+                actions.insert(id + 1, Action::Erase);
+                actions.insert(id + 2, Action::ClearSearch);
+                id += 3;
+                layout = builder.end_tab();
+
+                // Results only exist while searching, right after the picker
+                if !self.search.is_empty() {
+                    let results = self.search_results();
+                    let mut builder = layout.tab("Results");
+                    if results.is_empty() {
+                        builder = builder.line().button_stateless("Nothing found", id).endl();
+                        id += 1;
+                    }
+                    for (rt, rpath) in results.iter().take(PAGE_SIZE) {
+                        let name = self.tabs[*rt].node(rpath).map_or("".into(), Node::label);
+                        let name = format!("{} ({})", name, self.tabs[*rt].title);
+                        let (label, action) = self.entry_button(state, &name, t, *rt, rpath.clone());
+                        builder = builder.line().button_stateless(&label, id).endl();
+                        actions.insert(id, action);
+                        id += 1;
+                    }
+                    if results.len() > PAGE_SIZE {
+                        let more = format!("{} more, keep typing", results.len() - PAGE_SIZE);
+                        builder = builder.line().button_stateless(&more, id).endl();
+                        id += 1;
+                    }
+                    layout = builder.end_tab();
+                }
+                continue;
+            }
+
+            if !path.is_empty() {
+                builder = builder.line().button_stateless("< Back", id).endl();
+                actions.insert(id, Action::Back(t));
+                id += 1;
+            }
+
+            if let Some(sorting) = tab.sorting {
+                builder = builder.line().button_stateless(&format!("Sort: {}", sorting.mode.label()), id).endl();
+                actions.insert(id, Action::CycleSort(t));
+                id += 1;
+            }
+
+            let children = tab.children(path);
+            let page_count = children.len().div_ceil(PAGE_SIZE).max(1);
+            let page = self.pages[t].min(page_count - 1);
+            if page_count > 1 {
+                let mut line = builder.line();
+                if page > 0 {
+                    line = line.button_stateless("< Previous", id);
+                    actions.insert(id, Action::Page(t, page - 1));
+                    id += 1;
+                }
+                line = line.button_stateless(&format!("Page {}/{}", page + 1, page_count), id);
+                id += 1;
+                if page + 1 < page_count {
+                    line = line.button_stateless("Next >", id);
+                    actions.insert(id, Action::Page(t, page + 1));
+                    id += 1;
+                }
+                builder = line.endl();
+            }
+
+            for (i, node) in children.iter().enumerate().skip(page * PAGE_SIZE).take(PAGE_SIZE) {
+                let mut node_path = path.clone();
+                node_path.push(i);
+                let (label, action) = match node {
+                    Node::Entry(_) | Node::Rom(_) => {
+                        let (label, action) = self.entry_button(state, &node.label(), t, t, node_path);
+                        (label, Some(action))
+                    },
+                    Node::Folder(f) => (format!("{} >", f.name), Some(Action::Open(t, node_path))),
+                    Node::Note(text) => (text.clone(), None),
+                };
+
+                builder = builder.line().button_stateless(&label, id).endl();
+                if let Some(action) = action {
+                    actions.insert(id, action);
+                }
+                id += 1;
+            }
+            layout = builder.end_tab();
+        }
+
+        (actions, layout.build())
+    }
+}
+
+impl MenuLayout {
+    /// Scans every system's ROM directory
+    pub fn rom_tabs(&self, hashing: Hashing) -> Vec<RomTab> {
+        let mut roms = Vec::new();
+        // Only loaded once a system with a DAT file or an archive turns up
+        let mut hash_cache = None;
+        let mut archive_cache = None;
+
+        for (system_idx, system) in self.systems.iter().enumerate() {
+            if system.rom_directory.exists() {
+                let mut dirs = Vec::new();
+                let mut files = Vec::new();
+                if let Err(e) = scan_dir(&system.rom_directory, system.depth(), &mut HashSet::new(), &mut dirs, &mut files) {
+                    log_rust_error(&e, format!("Failed to open rom directory for {}", &system.name), LogPriority::Error);
+                    continue;
+                }
+                let Some(emulator) = self.emulators.iter().position(|e| e.systems.contains(&system.name)) else {
+                    log_error(format!("Failed to find suitable emulator for system {}", &system.name));
+                    continue;
+                };
+
+                hide_tracks(&mut files);
+
+                let dat = system.dat_file.as_ref().and_then(|path| match Dat::load(path) {
+                    Ok(dat) => Some(dat),
+                    Err(e) => {
+                        log_rust_error(&*e, format!("Failed to load the DAT file for {}, not verifying its ROMs", &system.name), LogPriority::Error);
+                        None
+                    },
+                });
+
+                let mut system_tab = Vec::new();
+                let mut verified = Vec::new();
+                let mut unhashed = 0;
+                let total = files.len();
+                for (i, (path, added)) in files.into_iter().enumerate() {
+                    let Some(filename) = path.file_name() else { continue };
+                    let mut inner = None;
+                    if split_name(filename, &system.file_extensions).is_none() && system.archives && is_archive(&path) {
+                        inner = find_in_archive(&path, &system.file_extensions, archive_cache.get_or_insert_with(|| FileCache::load("archives")));
+                        if inner.is_none() {
+                            log_error(format!("No ROM in archive {}. Expected one of: {:?}", filename.to_string_lossy(), &system.file_extensions));
+                            continue;
+                        }
+                    }
+                    // Archives are listed under the name of the ROM in them
+                    let inner_path = inner.as_ref().map(|i| i.path.clone());
+                    let shown = inner_path.as_deref().and_then(|i| i.file_name()).unwrap_or(filename);
+                    let Some((fancy_name, _)) = split_name(shown, &system.file_extensions) else {
+                        log_error(format!("Wrong file extension for file {}. Expected one of: {:?}", filename.to_string_lossy(), &system.file_extensions));
+                        continue;
+                    };
+                    let folder = match system.subfolders {
+                        Subfolders::Folders => path.parent()
+                            .and_then(|p| p.strip_prefix(&system.rom_directory).ok())
+                            .map(PathBuf::from)
+                            .unwrap_or_default(),
+                        Subfolders::Flatten => PathBuf::new(),
+                    };
+                    // Only for showing, the path stays as it is
+                    let mut name = fancy_name.to_string_lossy().into_owned();
+                    let dump = dat.as_ref().and_then(|dat| {
+                        let hash_cache = hash_cache.get_or_insert_with(HashCache::load);
+                        verified.push((path.clone(), inner.clone()));
+                        let hashes = match (hashing, &inner) {
+                            (Hashing::Now, Some(inner)) => hash_cache.hash_in_archive(&path, inner, (i + 1, total)),
+                            (Hashing::Now, None) => hash_cache.hash(&path, (i + 1, total)),
+                            (Hashing::Cached, inner) => {
+                                let hashes = hash_cache.cached(&path, inner.as_ref());
+                                if hashes.is_none() {
+                                    unhashed += 1;
+                                    return None;
+                                }
+                                hashes
+                            },
+                        };
+                        let Some(hashes) = hashes else { return Some(Dump::Unknown) };
+                        let (dump, title) = dat.check(&hashes, shown);
+                        if let Some(title) = title {
+                            name = title.to_string();
+                        }
+                        Some(dump)
+                    });
+                    system_tab.push(Rom {
+                        name,
+                        key: path.strip_prefix(&system.rom_directory).unwrap_or(&path).to_path_buf(),
+                        path,
+                        folder,
+                        inner: inner_path,
+                        system: system_idx,
+                        emulator,
+                        added,
+                        dump,
+                    });
+                }
+                let system_tab = group_discs(system_tab, &data_dir().join("smenu/m3u").join(&system.name));
+                roms.push(RomTab {
+                    name: system.name.clone(),
+                    sorting: Sorting {
+                        mode: system.sort,
+                        ignore_articles: system.ignore_articles,
+                    },
+                    roms: system_tab,
+                    dirs,
+                    verified,
+                    unhashed,
+                });
+            } else {
+                log_error(format!("{}'s rom directory, {}, does not exist, skipping ", &system.name, system.rom_directory.display()));
+            }
+        }
+
+        // Otherwise it's only got what was already cached, `hash_all` saves the rest
+        if let Some(hash_cache) = hash_cache.filter(|_| hashing == Hashing::Now) {
+            hash_cache.save();
+        }
+        if let Some(archive_cache) = archive_cache {
+            archive_cache.save();
+        }
+        roms
+    }
+
+    /// Builds the menu tree out of the config and what `rom_tabs` found, with ROM tabs sorted by
+    /// whatever was last picked for them in the menu or otherwise by the config
+    pub fn into_menu(self, roms: Vec<RomTab>, state: &State) -> Menu {
+        let startup = self.tabs.startup.is_some();
+        let mut tabs: Vec<Tab> = Vec::new();
+        let mut category_tabs = HashMap::new();
+        for category in self.categories.iter() {
+            category_tabs.insert(category.name.clone(), tabs.len());
+            tabs.push(Tab::new(category.name.clone(), category.title().to_string(), TabKind::Nodes));
+        }
+
+        for item in self.items.into_iter() {
+            // Undefined categories get reported by check(), the items still get a tab
+            let tab = *category_tabs.entry(item.category.clone()).or_insert_with(|| {
+                tabs.push(Tab::new(item.category.clone(), item.category.clone(), TabKind::Nodes));
+                tabs.len() - 1
+            });
+
+            let parent = item.parent.clone().unwrap_or_default();
+            let parent: Vec<&str> = parent.split('/').filter(|p| !p.is_empty()).collect();
+            Tab::insert(&mut tabs[tab].nodes, &parent, Node::Entry(item));
+        }
+
+        for rom_tab in roms.into_iter() {
+            let mut sorting = rom_tab.sorting;
+            if let Some(mode) = state.sort_modes.get(&rom_tab.name) {
+                sorting.mode = *mode;
+            }
+            let mut tab = Tab {
+                name: rom_tab.name.clone(),
+                title: rom_tab.name,
+                nodes: Vec::new(),
+                sorting: Some(sorting),
+                kind: TabKind::Nodes,
+            };
+            for rom in rom_tab.roms.into_iter() {
+                let dirs: Vec<String> = rom.folder.iter().map(|c| c.to_string_lossy().into_owned()).collect();
+                let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+                Tab::insert(&mut tab.nodes, &dirs, Node::Rom(rom));
+            }
+            tab.sort(state);
+            tabs.push(tab);
+        }
+
+        // Recent, favorites and search go through [tabs] like any other tab
+        tabs.insert(0, Tab::new(RECENT_TAB.to_string(), RECENT_TAB.to_string(), TabKind::Recent));
+        tabs.insert(0, Tab::new(FAVORITES_TAB.to_string(), FAVORITES_TAB.to_string(), TabKind::Favorites));
+        tabs.push(Tab::new(SEARCH_TAB.to_string(), SEARCH_TAB.to_string(), TabKind::Search));
+        let mut tabs = self.tabs.arrange(tabs, |t| t.name.as_str());
+
+        // Last, so it doesn't take the place of the startup tab
+        if !self.problems.is_empty() {
+            tabs.push(Tab {
+                name: "Warnings".to_string(),
+                title: "Warnings".to_string(),
+                nodes: self.problems.iter().map(|p| Node::Note(p.to_string())).collect(),
+                sorting: None,
+                kind: TabKind::Nodes,
+            });
+        }
+
+        let mut menu = Menu {
+            open: vec![Vec::new(); tabs.len()],
+            pages: vec![0; tabs.len()],
+            tabs,
+            current_tab: 0,
+            search: String::new(),
+            marking: false,
+            recent_count: self.tabs.recent_count(),
+            continue_last: self.tabs.continue_last(),
+            confirming: None,
+            ids: HashMap::new(),
+            systems: self.systems,
+            emulators: self.emulators,
+        };
+        menu.index();
+        menu.restore(state, !startup);
+        menu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(name: &str) -> Node {
+        Node::Rom(Rom {
+            name: name.to_string(),
+            path: PathBuf::from(format!("/roms/SNES/{}.sfc", name)),
+            key: PathBuf::from(format!("{}.sfc", name)),
+            folder: PathBuf::new(),
+            inner: None,
+            system: 0,
+            emulator: 0,
+            added: UNIX_EPOCH,
+            dump: None,
+        })
+    }
+
+    fn menu(tabs: Vec<Tab>) -> Menu {
+        let mut menu = Menu {
+            open: vec![Vec::new(); tabs.len()],
+            pages: vec![0; tabs.len()],
+            tabs,
+            current_tab: 0,
+            search: String::new(),
+            marking: false,
+            recent_count: 10,
+            continue_last: false,
+            confirming: None,
+            ids: HashMap::new(),
+            systems: Vec::new(),
+            emulators: Vec::new(),
+        };
+        menu.index();
+        menu
+    }
+
+    #[test]
+    fn launched_rom_moves_to_top() {
+        let mut state = State::default();
+        state.last_played.insert("SNES/Earthbound.sfc".to_string(), 2);
+        state.last_played.insert("SNES/Contra III.sfc".to_string(), 1);
+
+        let mut tab = Tab::new("SNES".to_string(), "SNES".to_string(), TabKind::Nodes);
+        tab.nodes = vec![rom("Contra III"), rom("Earthbound"), rom("Zombies Ate My Neighbors")];
+        tab.sorting = Some(Sorting {
+            mode: SortMode::LastPlayed,
+            ignore_articles: false,
+        });
+        tab.sort(&state);
+        let mut menu = menu(vec![tab]);
+        assert_eq!(menu.id(0, &[0]).as_deref(), Some("SNES/Earthbound.sfc"));
+
+        state.record_launch("SNES", "SNES/Zombies Ate My Neighbors.sfc");
+        menu.resort(0, &state);
+        let order: Vec<String> = (0..3).filter_map(|i| menu.id(0, &[i])).collect();
+        assert_eq!(order, ["SNES/Zombies Ate My Neighbors.sfc", "SNES/Earthbound.sfc", "SNES/Contra III.sfc"]);
+        // Looked up where it is now
+        assert_eq!(menu.find("SNES/Zombies Ate My Neighbors.sfc"), Some((0, vec![0])));
+    }
+
+    #[test]
+    fn folders_nest() {
+        let mut tab = Tab::new("SNES".to_string(), "SNES".to_string(), TabKind::Nodes);
+        Tab::insert(&mut tab.nodes, &[], rom("Contra III"));
+        Tab::insert(&mut tab.nodes, &["Hacks"], rom("Super Metroid Redesign"));
+        Tab::insert(&mut tab.nodes, &["Hacks", "Translations"], rom("Bahamut Lagoon"));
+        // Goes into the folder made above rather than a second one
+        Tab::insert(&mut tab.nodes, &["Hacks"], rom("Kaizo Mario"));
+
+        let names = |nodes: &[Node]| nodes.iter().map(|n| n.name().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(&tab.nodes), ["Contra III", "Hacks"]);
+        assert_eq!(names(tab.children(&[1])), ["Super Metroid Redesign", "Translations", "Kaizo Mario"]);
+        assert_eq!(names(tab.children(&[1, 1])), ["Bahamut Lagoon"]);
+
+        let menu = menu(vec![tab]);
+        assert_eq!(menu.find("SNES/Bahamut Lagoon.sfc"), Some((0, vec![1, 1, 0])));
+        assert_eq!(menu.id(0, &[1, 2]).as_deref(), Some("SNES/Kaizo Mario.sfc"));
+    }
+
+    #[test]
+    fn confirmation() {
+        let mut tab = Tab::new("SNES".to_string(), "SNES".to_string(), TabKind::Nodes);
+        tab.nodes = vec![rom("Contra III")];
+        let mut menu = menu(vec![tab, Tab::new(RECENT_TAB.to_string(), RECENT_TAB.to_string(), TabKind::Recent)]);
+
+        // Asked from the recent tab about an entry in the SNES tab
+        menu.ask(1, 0, vec![0]);
+        assert!(menu.confirming.is_some());
+        assert_eq!(menu.answer(), Some((0, vec![0])));
+        assert!(menu.confirming.is_none());
+        assert_eq!(menu.current_tab, 1);
+        assert_eq!(menu.answer(), None);
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Result, Context};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use libdogd::log_info;
+
+use crate::state::write_atomic;
+
+type Migration = fn(&mut toml::value::Table) -> Result<()>;
+
+/// Every migration, the one at index N upgrades a config from version N to N + 1
+const MIGRATIONS: &[Migration] = &[
+    v0_to_v1,
+    v1_to_v2,
+];
+
+pub const CURRENT_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Version 0 is the original format without a `version` key, version 1 only added the key
+fn v0_to_v1(_: &mut toml::value::Table) -> Result<()> {
+    Ok(())
+}
+
+/// Version 2 has categories declared with `[[category]]` instead of the fixed Tools and Programs,
+/// so those get declared for configs that put items in them, with the tab titles they used to have
+fn v1_to_v2(table: &mut toml::value::Table) -> Result<()> {
+    let used: Vec<&str> = match table.get("item") {
+        Some(toml::Value::Array(items)) => items.iter()
+            .filter_map(|i| i.get("category")?.as_str())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let declared: Vec<&str> = match table.get("category") {
+        Some(toml::Value::Array(categories)) => categories.iter()
+            .filter_map(|c| c.get("name")?.as_str())
+            .collect(),
+        Some(_) => return Err(anyhow!("category has to be an array of tables, like [[category]]")),
+        None => Vec::new(),
+    };
+
+    let mut new = Vec::new();
+    for (name, title) in [("Tools", Some("System Tools")), ("Programs", None)] {
+        if !used.contains(&name) || declared.contains(&name) {
+            continue;
+        }
+        let mut category = toml::value::Table::new();
+        category.insert("name".to_string(), name.into());
+        if let Some(title) = title {
+            category.insert("title".to_string(), title.into());
+        }
+        new.push(toml::Value::Table(category));
+    }
+
+    if new.is_empty() {
+        return Ok(());
+    }
+    if let toml::Value::Array(categories) = table.entry("category".to_string()).or_insert_with(|| toml::Value::Array(Vec::new())) {
+        categories.extend(new);
+    }
+    Ok(())
+}
+
+fn config_version(table: &toml::value::Table) -> Result<i64> {
+    match table.get("version") {
+        None => Ok(0),
+        Some(toml::Value::Integer(v)) if *v >= 0 => Ok(*v),
+        Some(v) => Err(anyhow!("Invalid config version {}", v)),
+    }
+}
+
+/// Brings a parsed config up to `CURRENT_VERSION` in place, returning the version it was at
+pub fn migrate(table: &mut toml::value::Table) -> Result<i64> {
+    let version = config_version(table)?;
+    if version > CURRENT_VERSION {
+        return Err(anyhow!("Config version {} is newer than the newest supported one, {}", version, CURRENT_VERSION));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(table).with_context(|| format!("Failed to upgrade config from version {} to {}", from, from + 1))?;
+    }
+    table.insert("version".to_string(), toml::Value::Integer(CURRENT_VERSION));
+    Ok(version)
+}
+
+/// `/etc/smenu.toml` at version 0 -> `/etc/smenu.toml.v0.bak`
+fn backup_path(p: &Path, version: i64) -> PathBuf {
+    let mut name = p.as_os_str().to_owned();
+    name.push(format!(".v{}.bak", version));
+    PathBuf::from(name)
+}
+
+/// Upgrades a config file on disk, keeping a backup of the old one. Comments and formatting
+/// don't survive this, the backup still has them. Returns whether anything had to be done.
+pub fn migrate_file(p: &Path) -> Result<bool> {
+    let text = fs::read_to_string(p).with_context(|| format!("Failed to read config file {}", p.display()))?;
+    let mut table: toml::value::Table = toml::from_str(&text)
+        .with_context(|| format!("Failed to deserialize config file {}", p.display()))?;
+
+    let version = migrate(&mut table).with_context(|| format!("Failed to upgrade {}", p.display()))?;
+    if version == CURRENT_VERSION {
+        return Ok(false);
+    }
+
+    let upgraded = toml::to_string(&toml::Value::Table(table)).context("Failed to serialize upgraded config")?;
+    let backup = backup_path(p, version);
+    fs::copy(p, &backup).with_context(|| format!("Failed to back up {} to {}", p.display(), backup.display()))?;
+
+    write_atomic(p, upgraded.as_bytes()).context("Failed to write upgraded config")?;
+
+    log_info(format!("Upgraded {} from version {} to {}, old one is at {}", p.display(), version, CURRENT_VERSION, backup.display()));
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(text: &str) -> toml::value::Table {
+        toml::from_str(text).unwrap()
+    }
+
+    /// Migrates `old`, checking it comes out as `new` and returning the version it was at
+    fn check(old: &str, new: &str) -> i64 {
+        let mut old = table(old);
+        let version = migrate(&mut old).unwrap();
+        assert_eq!(old, table(new));
+        version
+    }
+
+    #[test]
+    fn from_v0() {
+        let old = r#"
+[[category]]
+name = "Games"
+
+[[item]]
+name = "Settings"
+category = "Tools"
+
+[[item]]
+name = "Browser"
+category = "Programs"
+"#;
+        let new = r#"
+version = 2
+
+[[category]]
+name = "Games"
+
+[[category]]
+name = "Tools"
+title = "System Tools"
+
+[[category]]
+name = "Programs"
+
+[[item]]
+name = "Settings"
+category = "Tools"
+
+[[item]]
+name = "Browser"
+category = "Programs"
+"#;
+        assert_eq!(check(old, new), 0);
+    }
+
+    #[test]
+    fn from_v1() {
+        // Already declared categories keep their titles, unused ones don't get declared
+        let old = r#"
+version = 1
+
+[[category]]
+name = "Tools"
+title = "Tools"
+
+[[item]]
+name = "Settings"
+category = "Tools"
+"#;
+        assert_eq!(check(old, &old.replace("version = 1", "version = 2")), 1);
+        assert_eq!(check("version = 1", "version = 2"), 1);
+    }
+
+    #[test]
+    fn current() {
+        // Left alone, items can be in Tools without it being declared by now
+        let text = r#"
+version = 2
+
+[[item]]
+name = "Settings"
+category = "Tools"
+"#;
+        assert_eq!(check(text, text), CURRENT_VERSION);
+    }
+
+    #[test]
+    fn errors() {
+        let newer = migrate(&mut table(&format!("version = {}", CURRENT_VERSION + 1))).unwrap_err();
+        assert!(newer.to_string().contains("is newer than the newest supported one"));
+        assert!(migrate(&mut table("version = -1")).is_err());
+        assert!(migrate(&mut table("version = \"2\"")).is_err());
+
+        let mut text = table("version = 1\ncategory = \"Tools\"\n");
+        let e = migrate(&mut text).unwrap_err();
+        assert_eq!(format!("{:#}", e), "Failed to upgrade config from version 1 to 2: category has to be an array of tables, like [[category]]");
+    }
+
+    #[test]
+    fn backups() {
+        assert_eq!(backup_path(Path::new("/etc/smenu.toml"), 0), Path::new("/etc/smenu.toml.v0.bak"));
+    }
+}
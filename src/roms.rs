@@ -0,0 +1,152 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeSet, HashSet},
+};
+
+use libdogd::{log_info, LogPriority, log_rust_error};
+
+use crate::archive::{archive_files, ArchiveFile};
+use crate::cache::FileCache;
+
+/// Files and directories operating systems leave behind on SD cards, skipped without a word
+static JUNK: &[&str] = &["Thumbs.db", "desktop.ini", "__MACOSX", "$RECYCLE.BIN", "System Volume Information"];
+
+/// Whether `filename` is hidden or junk rather than something anyone put there on purpose
+pub fn is_junk(filename: &OsStr) -> bool {
+    filename.as_bytes().starts_with(b".") || JUNK.iter().any(|j| filename.to_str().is_some_and(|f| f.eq_ignore_ascii_case(j)))
+}
+
+/// Splits `filename` into its name and the extension out of `extensions` it has, ignoring case.
+/// The longest one wins if several match, so with both listed "cart.p8.png" is "cart" and
+/// "p8.png" rather than "cart.p8" and "png". Works on bytes, names don't have to be UTF-8.
+pub fn split_name<'a, 'b>(filename: &'a OsStr, extensions: &'b BTreeSet<String>) -> Option<(&'a OsStr, &'b str)> {
+    let bytes = filename.as_bytes();
+    extensions.iter()
+        .map(|e| e.trim_start_matches('.'))
+        .filter(|e| !e.is_empty() && bytes.len() > e.len() + 1)
+        .filter(|e| {
+            let dot = bytes.len() - e.len() - 1;
+            bytes[dot] == b'.' && bytes[dot + 1..].eq_ignore_ascii_case(e.as_bytes())
+        })
+        .max_by_key(|e| e.len())
+        .map(|e| (OsStr::from_bytes(&bytes[..bytes.len() - e.len() - 1]), e))
+}
+
+/// The ROM in an archive. Disc sheets win over other matches, so a zipped cue runs along with
+/// its tracks. Listings are cached, 7z ones take running 7z.
+pub fn find_in_archive(archive: &Path, extensions: &BTreeSet<String>, cache: &mut FileCache<Vec<ArchiveFile>>) -> Option<ArchiveFile> {
+    let files = cache.get(archive, archive, || match archive_files(archive) {
+        Ok(f) => Some(f),
+        Err(e) => {
+            log_rust_error(&*e, format!("Failed to look into {}", archive.display()), LogPriority::Error);
+            None
+        },
+    })?;
+
+    let is_sheet = |p: &Path| ["cue", "gdi", "ccd", "m3u"].iter().any(|s| p.extension().is_some_and(|e| e.eq_ignore_ascii_case(s)));
+    files.into_iter()
+        .filter(|f| f.path.file_name().is_some_and(|n| split_name(n, extensions).is_some()))
+        .min_by_key(|f| !is_sheet(&f.path))
+}
+
+/// Lists the files in `dir` and its subdirectories, `depth` levels down at most. Symlinks are
+/// followed, directories already seen through another link are skipped so loops end. Every
+/// directory that got listed ends up in `dirs`.
+pub fn scan_dir(dir: &Path, depth: usize, seen: &mut HashSet<(u64, u64)>, dirs: &mut Vec<PathBuf>, files: &mut Vec<(PathBuf, SystemTime)>) -> io::Result<()> {
+    let meta = fs::metadata(dir)?;
+    if !seen.insert((meta.dev(), meta.ino())) {
+        log_info(format!("Skipping {}, it was already scanned", dir.display()));
+        return Ok(());
+    }
+    dirs.push(dir.to_path_buf());
+
+    for file in fs::read_dir(dir)? {
+        let Ok(file) = file else { continue };
+        if is_junk(&file.file_name()) {
+            continue;
+        }
+        let path = file.path();
+        // Broken symlinks end up here
+        let Ok(meta) = fs::metadata(&path) else { continue };
+        if meta.is_dir() {
+            if depth == 0 {
+                continue;
+            }
+            if let Err(e) = scan_dir(&path, depth - 1, seen, dirs, files) {
+                log_rust_error(&e, format!("Failed to scan {}", path.display()), LogPriority::Error);
+            }
+        } else {
+            files.push((path, meta.modified().unwrap_or(UNIX_EPOCH)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split<'a>(filename: &'a str, extensions: &[&str]) -> Option<(&'a str, String)> {
+        let extensions: BTreeSet<String> = extensions.iter().map(|e| e.to_string()).collect();
+        split_name(OsStr::new(filename), &extensions).map(|(name, ext)| (name.to_str().unwrap(), ext.to_string()))
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(split("Super Mario Bros. 3.nes", &["nes"]), Some(("Super Mario Bros. 3", "nes".into())));
+        assert_eq!(split("GAME.NES", &["nes"]), Some(("GAME", "nes".into())));
+        // With or without the dot in the config
+        assert_eq!(split("game.sfc", &[".sfc", "smc"]), Some(("game", "sfc".into())));
+        assert_eq!(split("cart.p8.png", &["png", "p8.png"]), Some(("cart", "p8.png".into())));
+        assert_eq!(split("cart.png", &["p8.png"]), None);
+        assert_eq!(split("readme.txt", &["nes"]), None);
+        // Nothing left for the name
+        assert_eq!(split(".nes", &["nes"]), None);
+        assert_eq!(split("nes", &["nes"]), None);
+    }
+
+    #[test]
+    fn junk() {
+        assert!(is_junk(OsStr::new(".DS_Store")));
+        assert!(is_junk(OsStr::new("._Game.nes")));
+        assert!(is_junk(OsStr::new("THUMBS.DB")));
+        assert!(is_junk(OsStr::new("__MACOSX")));
+        assert!(!is_junk(OsStr::new("Game.nes")));
+        assert!(!is_junk(OsStr::new("Thumbs.db.nes")));
+    }
+
+    #[test]
+    fn scan_depth_and_loops() {
+        let root = std::env::temp_dir().join(format!("smenu-test-{}-scan", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        fs::write(root.join("a.nes"), "").unwrap();
+        fs::write(root.join("sub/b.nes"), "").unwrap();
+        fs::write(root.join("sub/deeper/c.nes"), "").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("sub/loop")).unwrap();
+
+        let scan = |depth| {
+            let (mut dirs, mut files) = (Vec::new(), Vec::new());
+            scan_dir(&root, depth, &mut HashSet::new(), &mut dirs, &mut files).unwrap();
+            let relative = |p: &Path| p.strip_prefix(&root).unwrap().to_string_lossy().into_owned();
+            let mut dirs: Vec<String> = dirs.iter().map(|d| relative(d)).collect();
+            let mut files: Vec<String> = files.iter().map(|(f, _)| relative(f)).collect();
+            dirs.sort();
+            files.sort();
+            (dirs, files)
+        };
+
+        assert_eq!(scan(0), (vec!["".to_string()], vec!["a.nes".to_string()]));
+        assert_eq!(scan(1), (vec!["".to_string(), "sub".to_string()], vec!["a.nes".to_string(), "sub/b.nes".to_string()]));
+        // The link back to the top is only followed once, as the top has been scanned already
+        let (dirs, files) = scan(10);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(dirs, ["", "sub", "sub/deeper"]);
+        assert_eq!(files, ["a.nes", "sub/b.nes", "sub/deeper/c.nes"]);
+    }
+}
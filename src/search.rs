@@ -0,0 +1,76 @@
+/// How well a name matched a search, better matches compare lower
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Match {
+    /// The search appears as is, at this character
+    Substring(usize),
+    /// The search's characters appear in order, spread over this many characters
+    Fuzzy(usize),
+}
+
+/// Characters offered by the on-screen picker, one row each
+pub static PICKER_ROWS: &[&str] = &["ABCDEFGHI", "JKLMNOPQR", "STUVWXYZ", "0123456789", "-'&:!."];
+
+/// Matches `name` against `query`, ignoring case. Spaces in the query are skipped for fuzzy
+/// matching, so "mario 3" still finds "Super Mario Bros. 3".
+pub fn find(name: &str, query: &str) -> Option<Match> {
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    if query.is_empty() {
+        return None;
+    }
+
+    if let Some(pos) = name.windows(query.len()).position(|w| w == &query[..]) {
+        return Some(Match::Substring(pos));
+    }
+
+    let mut wanted = query.iter().filter(|c| !c.is_whitespace()).peekable();
+    let mut start = None;
+    for (i, c) in name.iter().enumerate() {
+        if wanted.peek() == Some(&c) {
+            start.get_or_insert(i);
+            wanted.next();
+            if wanted.peek().is_none() {
+                return Some(Match::Fuzzy(i + 1 - start.unwrap_or(0)));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substrings_and_fuzzy() {
+        assert_eq!(find("Tetris", "TET"), Some(Match::Substring(0)));
+        assert_eq!(find("Super Mario Bros. 3", "mario"), Some(Match::Substring(6)));
+        // Spread from the "m" of Mario to the "3"
+        assert_eq!(find("Super Mario Bros. 3", "mario 3"), Some(Match::Fuzzy(13)));
+        assert_eq!(find("Super Mario Bros. 3", "3 mario"), None);
+        assert_eq!(find("Tetris", "zelda"), None);
+    }
+
+    #[test]
+    fn empty_query() {
+        assert_eq!(find("Tetris", ""), None);
+        assert_eq!(find("", ""), None);
+    }
+
+    #[test]
+    fn ranking() {
+        let mut matches = vec![
+            find("Super Mario Bros. 3", "mario 3"),
+            find("Dr. Mario", "mario"),
+            find("Mario Kart", "mario"),
+            find("Mario Bros.", "mb"),
+        ];
+        matches.sort();
+        assert_eq!(matches, vec![
+            Some(Match::Substring(0)),
+            Some(Match::Substring(4)),
+            Some(Match::Fuzzy(7)),
+            Some(Match::Fuzzy(13)),
+        ]);
+    }
+}
@@ -0,0 +1,148 @@
+use serde::{Serialize, Deserialize};
+use std::{
+    cmp::Ordering,
+    iter::Peekable,
+    str::Chars,
+};
+
+/// How the entries of a ROM tab are ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortMode {
+    #[default]
+    Name,
+    LastPlayed,
+    MostPlayed,
+    DateAdded,
+}
+
+impl SortMode {
+    /// The mode after this one, for switching between them from the menu
+    pub fn next(self) -> SortMode {
+        match self {
+            SortMode::Name => SortMode::LastPlayed,
+            SortMode::LastPlayed => SortMode::MostPlayed,
+            SortMode::MostPlayed => SortMode::DateAdded,
+            SortMode::DateAdded => SortMode::Name,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SortMode::Name => "Name",
+            SortMode::LastPlayed => "Last played",
+            SortMode::MostPlayed => "Most played",
+            SortMode::DateAdded => "Date added",
+        }
+    }
+}
+
+static ARTICLES: &[&str] = &["the ", "a ", "an "];
+
+/// `name` without a leading "The", "A" or "An"
+pub fn strip_article(name: &str) -> &str {
+    for article in ARTICLES {
+        if name.len() > article.len() && name.is_char_boundary(article.len()) && name[..article.len()].eq_ignore_ascii_case(article) {
+            return &name[article.len()..];
+        }
+    }
+    name
+}
+
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut ret = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        ret.push(c);
+    }
+    ret
+}
+
+/// Compares names the way people expect them to be ordered: case-insensitively and with
+/// runs of digits compared as numbers, so "Game 2" comes before "Game 10"
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut x = a.chars().peekable();
+    let mut y = b.chars().peekable();
+
+    loop {
+        let ord = match (x.peek(), y.peek()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(c), Some(d)) if c.is_ascii_digit() && d.is_ascii_digit() => {
+                let m = take_number(&mut x);
+                let n = take_number(&mut y);
+                let (m, n) = (m.trim_start_matches('0'), n.trim_start_matches('0'));
+                m.len().cmp(&n.len()).then_with(|| m.cmp(n))
+            },
+            (Some(c), Some(d)) => {
+                let ord = c.to_lowercase().cmp(d.to_lowercase());
+                x.next();
+                y.next();
+                ord
+            },
+        };
+
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+
+    // Only differing in case or leading zeros, still has to be deterministic
+    a.cmp(b)
+}
+
+/// A name that orders by `natural_cmp`, for sorting with precomputed keys
+#[derive(Debug, PartialEq, Eq)]
+pub struct NaturalKey(pub String);
+
+impl Ord for NaturalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        natural_cmp(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for NaturalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut keys: Vec<NaturalKey> = names.iter().map(|n| NaturalKey(n.to_string())).collect();
+        keys.sort();
+        keys.into_iter().map(|k| k.0).collect()
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(natural_cmp("Game 2", "Game 10"), Ordering::Less);
+        assert_eq!(natural_cmp("Game 10", "Game 9"), Ordering::Greater);
+        assert_eq!(natural_cmp("Game 007", "Game 7"), Ordering::Less);
+        assert_eq!(sorted(&["Game 10", "Game", "Game 2", "Game 1b", "Game 1a"]), ["Game", "Game 1a", "Game 1b", "Game 2", "Game 10"]);
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("ZELDA", "metroid"), Ordering::Greater);
+        // Still ordered when only the case differs
+        assert_eq!(natural_cmp("Tetris", "tetris"), Ordering::Less);
+        assert_eq!(natural_cmp("Tetris", "Tetris"), Ordering::Equal);
+    }
+
+    #[test]
+    fn articles() {
+        assert_eq!(strip_article("The Legend of Zelda"), "Legend of Zelda");
+        assert_eq!(strip_article("a Boy and His Blob"), "Boy and His Blob");
+        assert_eq!(strip_article("AN American Tail"), "American Tail");
+        // Only whole words, and never the whole name
+        assert_eq!(strip_article("Theme Park"), "Theme Park");
+        assert_eq!(strip_article("Another World"), "Another World");
+        assert_eq!(strip_article("The "), "The ");
+        assert_eq!(strip_article("Ä game"), "Ä game");
+    }
+}
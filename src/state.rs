@@ -0,0 +1,163 @@
+use serde::{Serialize, Deserialize};
+use anyhow::{Result, Context};
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+    collections::BTreeMap,
+};
+
+use libdogd::{log_info, LogPriority, log_rust_error};
+
+use crate::sort::SortMode;
+use crate::vars::data_dir;
+
+/// Runtime data smenu keeps between runs, separate from the hand-edited config
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_tab: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_entry: Option<String>,
+    /// Entry IDs, in the order they were marked
+    pub favorites: Vec<String>,
+    /// Keyed by entry ID
+    pub play_counts: BTreeMap<String, u64>,
+    /// Seconds since the epoch, keyed by entry ID
+    pub last_played: BTreeMap<String, u64>,
+    /// Sort mode picked in the menu, keyed by tab name
+    pub sort_modes: BTreeMap<String, SortMode>,
+}
+
+pub fn default_state_path() -> PathBuf {
+    data_dir().join("smenu/state.toml")
+}
+
+/// Writes `contents` to `p` so that after a crash or power loss it has either the old or the
+/// new contents, never a mix of both
+pub fn write_atomic(p: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match p.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let mut tmp = p.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(contents).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    file.sync_all().with_context(|| format!("Failed to sync {}", tmp.display()))?;
+    drop(file);
+
+    fs::rename(&tmp, p).with_context(|| format!("Failed to replace {}", p.display()))?;
+    // The rename only sticks once the directory itself is on disk
+    File::open(dir).and_then(|d| d.sync_all()).with_context(|| format!("Failed to sync {}", dir.display()))?;
+    Ok(())
+}
+
+impl State {
+    /// A missing or broken state file isn't worth failing over, it just starts out empty
+    pub fn load(p: &Path) -> State {
+        let text = match fs::read_to_string(p) {
+            Ok(t) => t,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log_info(format!("No state file at {}, starting fresh", p.display()));
+                return State::default();
+            },
+            Err(e) => {
+                log_rust_error(&e, format!("Failed to read state file {}", p.display()), LogPriority::Error);
+                return State::default();
+            },
+        };
+
+        match toml::from_str(&text) {
+            Ok(s) => s,
+            Err(e) => {
+                log_rust_error(&e, format!("Failed to parse state file {}, starting fresh", p.display()), LogPriority::Error);
+                State::default()
+            },
+        }
+    }
+
+    pub fn save(&self, p: &Path) -> Result<()> {
+        let text = toml::to_string(self).context("Failed to serialize state")?;
+        write_atomic(p, text.as_bytes())
+    }
+
+    pub fn is_favorite(&self, id: &str) -> bool {
+        self.favorites.iter().any(|f| f == id)
+    }
+
+    /// Adds `id` to the favorites or removes it if it's already there
+    pub fn toggle_favorite(&mut self, id: String) {
+        match self.favorites.iter().position(|f| *f == id) {
+            Some(i) => { self.favorites.remove(i); },
+            None => self.favorites.push(id),
+        }
+    }
+
+    /// Counts a launch of the entry with `id`, started from the tab named `tab`
+    pub fn record_launch(&mut self, tab: &str, id: &str) {
+        self.last_tab = Some(tab.to_string());
+        self.last_entry = Some(id.to_string());
+        *self.play_counts.entry(id.to_string()).or_insert(0) += 1;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        self.last_played.insert(id.to_string(), now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("smenu-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn atomic_write() {
+        let dir = temp_dir("atomic");
+        let p = dir.join("nested/state.toml");
+        write_atomic(&p, b"old").unwrap();
+        write_atomic(&p, b"new").unwrap();
+        let contents = fs::read(&p).unwrap();
+        let left: Vec<PathBuf> = fs::read_dir(p.parent().unwrap()).unwrap().map(|e| e.unwrap().path()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(contents, b"new");
+        // The temporary file got renamed over it
+        assert_eq!(left, vec![p]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("state");
+        let p = dir.join("state.toml");
+        let mut state = State::default();
+        state.toggle_favorite("SNES/Earthbound.sfc".to_string());
+        state.toggle_favorite("Tools/Htop".to_string());
+        state.record_launch("Recent", "SNES/Earthbound.sfc");
+        state.record_launch("SNES", "SNES/Earthbound.sfc");
+        state.sort_modes.insert("SNES".to_string(), SortMode::MostPlayed);
+        state.save(&p).unwrap();
+        let loaded = State::load(&p);
+        // Missing and broken files start out empty
+        fs::write(&p, "favorites = 3").unwrap();
+        let broken = State::load(&p);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.last_tab.as_deref(), Some("SNES"));
+        assert_eq!(loaded.last_entry.as_deref(), Some("SNES/Earthbound.sfc"));
+        assert_eq!(loaded.favorites, state.favorites);
+        assert_eq!(loaded.play_counts.get("SNES/Earthbound.sfc"), Some(&2));
+        assert_eq!(loaded.last_played, state.last_played);
+        assert_eq!(loaded.sort_modes.get("SNES"), Some(&SortMode::MostPlayed));
+        assert!(broken.favorites.is_empty());
+        assert!(State::load(&dir.join("missing.toml")).favorites.is_empty());
+    }
+}
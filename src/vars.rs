@@ -0,0 +1,188 @@
+use std::{
+    env,
+    fs,
+    path::PathBuf,
+    collections::HashMap,
+};
+
+use crate::config::MenuLayout;
+use crate::diagnostics::Problem;
+
+/// Where the board's model name can be found, first one that exists wins
+static DEVICE_NAME_FILES: &[&str] = &[
+    "/proc/device-tree/model",
+    "/sys/class/dmi/id/product_name",
+];
+
+/// Expands `${VAR}` and a leading `~` in config values. Besides the process environment
+/// there's `${DATA}`, the data partition root, and `${DEVICE}`, the board's model name.
+/// Both can be overridden with `SMENU_DATA` and `SMENU_DEVICE`.
+pub struct Variables {
+    builtin: HashMap<&'static str, String>,
+    home: Option<String>,
+}
+
+/// Root of the data partition, `/data` unless overridden with `SMENU_DATA`
+pub fn data_dir() -> PathBuf {
+    env::var_os("SMENU_DATA").map_or_else(|| PathBuf::from("/data"), PathBuf::from)
+}
+
+fn device_name() -> String {
+    DEVICE_NAME_FILES.iter()
+        .find_map(|p| fs::read_to_string(p).ok())
+        .map(|s| s.trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+impl Variables {
+    pub fn new() -> Self {
+        let mut builtin = HashMap::new();
+        builtin.insert("DATA", data_dir().to_string_lossy().to_string());
+        builtin.insert("DEVICE", env::var("SMENU_DEVICE").unwrap_or_else(|_| device_name()));
+
+        Self {
+            builtin,
+            home: env::var("HOME").ok(),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        self.builtin.get(name).cloned().or_else(|| env::var(name).ok())
+    }
+
+    /// `$$` is a literal `$`, a `$` not followed by `{` is left alone
+    pub fn expand(&self, s: &str) -> Result<String, String> {
+        let mut ret = String::new();
+        let mut rest = s;
+
+        if rest == "~" || rest.starts_with("~/") {
+            if let Some(home) = &self.home {
+                ret.push_str(home);
+                rest = &rest[1..];
+            }
+        }
+
+        while let Some(i) = rest.find('$') {
+            ret.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            if let Some(r) = rest.strip_prefix('$') {
+                ret.push('$');
+                rest = r;
+                continue;
+            }
+            let Some(r) = rest.strip_prefix('{') else {
+                ret.push('$');
+                continue;
+            };
+            let Some(end) = r.find('}') else {
+                return Err(format!("Unterminated variable in {}", s));
+            };
+
+            let name = &r[..end];
+            let value = self.lookup(name).ok_or_else(|| format!("Unknown variable {} in {}", name, s))?;
+            ret.push_str(&value);
+            rest = &r[end + 1..];
+        }
+
+        ret.push_str(rest);
+        Ok(ret)
+    }
+
+    fn expand_string(&self, s: &mut String, problems: &mut Vec<Problem>) {
+        match self.expand(s) {
+            Ok(expanded) => *s = expanded,
+            Err(e) => problems.push(Problem::new(e)),
+        }
+    }
+
+    fn expand_path(&self, p: &mut PathBuf, problems: &mut Vec<Problem>) {
+        let Some(s) = p.to_str() else { return };
+        match self.expand(s) {
+            Ok(expanded) => *p = PathBuf::from(expanded),
+            Err(e) => problems.push(Problem::new(e)),
+        }
+    }
+
+    fn expand_command(&self, executable: &mut PathBuf, args: &mut [String], env: &mut [(String, String)], problems: &mut Vec<Problem>) {
+        self.expand_path(executable, problems);
+        for arg in args.iter_mut() {
+            self.expand_string(arg, problems);
+        }
+        for (_, value) in env.iter_mut() {
+            self.expand_string(value, problems);
+        }
+    }
+}
+
+impl MenuLayout {
+    /// Expands variables in every path, argument and environment value, values that fail to
+    /// expand are left as they were
+    pub fn expand_variables(&mut self, vars: &Variables) {
+        let mut problems = Vec::new();
+
+        for item in self.items.iter_mut() {
+            vars.expand_command(&mut item.executable, &mut item.args, &mut item.env, &mut problems);
+        }
+        for emulator in self.emulators.iter_mut() {
+            vars.expand_command(&mut emulator.executable, &mut emulator.args, &mut emulator.env, &mut problems);
+        }
+        for system in self.systems.iter_mut() {
+            vars.expand_path(&mut system.rom_directory, &mut problems);
+            if let Some(dat_file) = &mut system.dat_file {
+                vars.expand_path(dat_file, &mut problems);
+            }
+        }
+
+        self.problems.extend(problems);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fixed values rather than whatever the machine running the tests has
+    fn vars() -> Variables {
+        Variables {
+            builtin: HashMap::from([("DATA", "/data".to_string()), ("DEVICE", "RG35XX".to_string())]),
+            home: Some("/home/user".to_string()),
+        }
+    }
+
+    #[test]
+    fn variables() {
+        let vars = vars();
+        assert_eq!(vars.expand("${DATA}/roms/${DEVICE}").unwrap(), "/data/roms/RG35XX");
+        assert_eq!(vars.expand("no variables").unwrap(), "no variables");
+    }
+
+    #[test]
+    fn dollars() {
+        let vars = vars();
+        assert_eq!(vars.expand("$${DATA}").unwrap(), "${DATA}");
+        assert_eq!(vars.expand("cost: 5$").unwrap(), "cost: 5$");
+        assert_eq!(vars.expand("$HOME and $").unwrap(), "$HOME and $");
+        assert_eq!(vars.expand("$$${DATA}").unwrap(), "$/data");
+    }
+
+    #[test]
+    fn errors() {
+        let vars = vars();
+        assert_eq!(vars.expand("${DATA"), Err("Unterminated variable in ${DATA".to_string()));
+        assert_eq!(vars.expand("${SMENU_TEST_UNSET}"), Err("Unknown variable SMENU_TEST_UNSET in ${SMENU_TEST_UNSET}".to_string()));
+    }
+
+    #[test]
+    fn home() {
+        let vars = vars();
+        assert_eq!(vars.expand("~").unwrap(), "/home/user");
+        assert_eq!(vars.expand("~/roms").unwrap(), "/home/user/roms");
+        // Only a leading ~ on its own, not other users' homes or one in the middle
+        assert_eq!(vars.expand("~other/roms").unwrap(), "~other/roms");
+        assert_eq!(vars.expand("/roms/~").unwrap(), "/roms/~");
+
+        let homeless = Variables { home: None, ..vars };
+        assert_eq!(homeless.expand("~/roms").unwrap(), "~/roms");
+    }
+}
@@ -0,0 +1,176 @@
+use nix::sys::{
+    inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+    signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+use anyhow::Result;
+use std::{
+    ffi::OsString,
+    path::{Component, Path},
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use libdogd::{log_debug, LogPriority, log_rust_error};
+
+/// How long things have to stay quiet after a change before reloading, editors and file
+/// managers tend to do a bunch of writes and renames in a row
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Has the menu rebuilt on the next input, for work done in the background
+pub fn request_reload() {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGHUP request a reload. sgui has no way to wake up or time out while it waits for
+/// input, so like any other reload it happens on the next button press.
+pub fn reload_on_sighup() -> Result<()> {
+    let action = SigAction::new(SigHandler::Handler(on_sighup), SaFlags::SA_RESTART, SigSet::empty());
+    unsafe { sigaction(Signal::SIGHUP, &action) }?;
+    Ok(())
+}
+
+/// File name the watch is limited to, `None` for anything in the directory
+type Watches = Arc<Mutex<Vec<(WatchDescriptor, Option<OsString>)>>>;
+
+/// Keeps an eye on the config and ROM directories. Events are read on a thread of their own,
+/// which requests a reload once things have settled down. The GUI only gets to it after the
+/// next input, it can't be woken up while waiting for one.
+pub struct Watcher {
+    inotify: Option<Inotify>,
+    watches: Watches,
+}
+
+/// The parent of a bare file name like `smenu.toml` is empty, which means the current directory
+fn or_current(dir: &Path) -> &Path {
+    if dir.as_os_str().is_empty() { Path::new(".") } else { dir }
+}
+
+/// Waits up to `timeout` for `inotify` to have something to read
+fn readable(inotify: Inotify, timeout: Duration) -> bool {
+    let mut fd = libc::pollfd {
+        fd: inotify.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+fn watch_events(inotify: Inotify, watches: Watches) {
+    let relevant = |inotify: Inotify| match inotify.read_events() {
+        Ok(events) => {
+            let watches = watches.lock().unwrap();
+            events.iter().any(|ev| watches.iter().any(|(wd, name)| *wd == ev.wd && (name.is_none() || *name == ev.name)))
+        },
+        Err(e) => {
+            log_rust_error(e, "Failed to read inotify events", LogPriority::Error);
+            thread::sleep(SETTLE_TIME);
+            false
+        },
+    };
+
+    loop {
+        if !relevant(inotify) {
+            continue;
+        }
+        while readable(inotify, SETTLE_TIME) {
+            relevant(inotify);
+        }
+        log_debug("Watched files changed, reloading");
+        request_reload();
+    }
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        let inotify = match Inotify::init(InitFlags::IN_CLOEXEC) {
+            Ok(i) => Some(i),
+            Err(e) => {
+                log_rust_error(e, "Failed to set up inotify, only reloading on SIGHUP", LogPriority::Error);
+                None
+            },
+        };
+
+        let watches = Watches::default();
+        if let Some(inotify) = inotify {
+            let watches = watches.clone();
+            thread::spawn(move || watch_events(inotify, watches));
+        }
+
+        Self {
+            inotify,
+            watches,
+        }
+    }
+
+    fn add(&mut self, dir: &Path, name: Option<OsString>, flags: AddWatchFlags) {
+        let Some(inotify) = self.inotify else { return };
+        match inotify.add_watch(dir, flags) {
+            Ok(wd) => self.watches.lock().unwrap().push((wd, name)),
+            Err(e) => log_debug(format!("Not watching {}: {}", dir.display(), e)),
+        }
+    }
+
+    /// Watches for `p` showing up when it doesn't exist yet, through the closest directory
+    /// above it that does. Once it's there the reload sets up the real watch.
+    fn watch_missing(&mut self, p: &Path) {
+        let Some(dir) = p.ancestors().skip(1).find(|a| or_current(a).is_dir()) else { return };
+        let Some(Component::Normal(name)) = p.strip_prefix(dir).ok().and_then(|rest| rest.components().next()) else { return };
+        self.add(or_current(dir), Some(name.to_os_string()), AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO);
+    }
+
+    /// Watches a single file, through its directory so it being replaced by a rename is noticed too
+    pub fn watch_file(&mut self, p: &Path) {
+        let (Some(dir), Some(name)) = (p.parent(), p.file_name()) else { return };
+        let dir = or_current(dir);
+        if !dir.is_dir() {
+            return self.watch_missing(p);
+        }
+        let flags = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM | AddWatchFlags::IN_MOVED_TO;
+        self.add(dir, Some(name.to_os_string()), flags);
+    }
+
+    /// Watches a directory and the contents of every file in it
+    pub fn watch_dir(&mut self, p: &Path) {
+        if !p.is_dir() {
+            return self.watch_missing(p);
+        }
+        let flags = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM | AddWatchFlags::IN_MOVED_TO;
+        self.add(p, None, flags);
+    }
+
+    /// Watches only files being added to or removed from a directory, so emulators writing
+    /// next to ROMs don't cause a reload every time
+    pub fn watch_listing(&mut self, p: &Path) {
+        let flags = AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM | AddWatchFlags::IN_MOVED_TO;
+        self.add(p, None, flags);
+    }
+
+    pub fn clear(&mut self) {
+        if let Some(inotify) = self.inotify {
+            for (wd, _) in self.watches.lock().unwrap().drain(..) {
+                // Fails for directories that are gone already, which is fine
+                let _ = inotify.rm_watch(wd);
+            }
+        }
+    }
+
+    /// Whether the menu should be rebuilt now, either because of SIGHUP or because something
+    /// watched changed and has been left alone for a while
+    pub fn reload_requested(&self) -> bool {
+        RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+    }
+}